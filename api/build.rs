@@ -0,0 +1,4 @@
+fn main() {
+    // embedded migrations are only picked up when the crate is rebuilt
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -1,12 +1,14 @@
-CREATE EXTENSION pg_trgm;
-CREATE EXTENSION citext;
+-- Databases set up by the old init.sql already have all of this, so every
+-- statement here tolerates it.
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE EXTENSION IF NOT EXISTS citext;
 
-CREATE FUNCTION concat_stack(TEXT[]) 
+CREATE OR REPLACE FUNCTION concat_stack(TEXT[])
   RETURNS TEXT LANGUAGE sql IMMUTABLE AS $$
     SELECT lower(array_to_string(COALESCE($1, '{}'::VARCHAR(32)[]), ''))
 $$;
 
-CREATE TABLE people (
+CREATE TABLE IF NOT EXISTS people (
     id UUID PRIMARY KEY,
     name VARCHAR(100) NOT NULL,
     nickname CITEXT UNIQUE NOT NULL,
@@ -15,5 +17,5 @@ CREATE TABLE people (
     search_term TEXT GENERATED ALWAYS AS (lower(name) || lower(nickname) || concat_stack(stack)) STORED
 );
 
-CREATE INDEX people_search_term_trigram_index ON people
+CREATE INDEX IF NOT EXISTS people_search_term_trigram_index ON people
   USING gin (search_term gin_trgm_ops);
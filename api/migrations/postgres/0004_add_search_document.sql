@@ -0,0 +1,25 @@
+-- Full-text search over the words of a person, accent insensitive and
+-- stemmed as portuguese. `unaccent` is only stable, as its dictionary could
+-- change, so it's wrapped in an immutable function to be usable in a
+-- generated column.
+CREATE EXTENSION IF NOT EXISTS unaccent;
+
+CREATE OR REPLACE FUNCTION fold_accents(TEXT)
+  RETURNS TEXT LANGUAGE sql IMMUTABLE PARALLEL SAFE AS $$
+    SELECT public.unaccent('public.unaccent'::regdictionary, $1)
+$$;
+
+CREATE OR REPLACE FUNCTION stack_words(TEXT[])
+  RETURNS TEXT LANGUAGE sql IMMUTABLE PARALLEL SAFE AS $$
+    SELECT array_to_string(COALESCE($1, '{}'::VARCHAR(32)[]), ' ')
+$$;
+
+ALTER TABLE people ADD COLUMN IF NOT EXISTS search_document TSVECTOR
+  GENERATED ALWAYS AS (
+    setweight(to_tsvector('portuguese', fold_accents(nickname::text)), 'A') ||
+    setweight(to_tsvector('portuguese', fold_accents(name)), 'B') ||
+    setweight(to_tsvector('portuguese', fold_accents(stack_words(stack))), 'C')
+  ) STORED;
+
+CREATE INDEX IF NOT EXISTS people_search_document_index ON people
+  USING gin (search_document);
@@ -0,0 +1,13 @@
+-- SQLite has neither CITEXT nor arrays, so the nickname is compared with
+-- NOCASE and the stack is kept as a JSON array. The search term is written
+-- along with the person, as generated columns can't read JSON arrays.
+CREATE TABLE IF NOT EXISTS people (
+    id BLOB PRIMARY KEY,
+    name TEXT NOT NULL,
+    nickname TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    birthday TEXT NOT NULL,
+    stack TEXT,
+    updated_at TEXT NOT NULL,
+    version INTEGER NOT NULL DEFAULT 1,
+    search_term TEXT NOT NULL
+);
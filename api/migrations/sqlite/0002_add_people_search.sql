@@ -0,0 +1,29 @@
+-- Full-text search over the words of a person. SQLite has no portuguese
+-- stemmer, so accents are folded but words are matched by prefix instead.
+-- The people rowids can change on VACUUM, so the index is kept by id.
+CREATE VIRTUAL TABLE IF NOT EXISTS people_search USING fts5(
+    id UNINDEXED,
+    nickname,
+    name,
+    stack,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+INSERT INTO people_search (id, nickname, name, stack)
+SELECT id, nickname, name, stack FROM people
+WHERE id NOT IN (SELECT id FROM people_search);
+
+CREATE TRIGGER IF NOT EXISTS people_search_insert AFTER INSERT ON people BEGIN
+    INSERT INTO people_search (id, nickname, name, stack)
+    VALUES (new.id, new.nickname, new.name, new.stack);
+END;
+
+CREATE TRIGGER IF NOT EXISTS people_search_update AFTER UPDATE ON people BEGIN
+    DELETE FROM people_search WHERE id = old.id;
+    INSERT INTO people_search (id, nickname, name, stack)
+    VALUES (new.id, new.nickname, new.name, new.stack);
+END;
+
+CREATE TRIGGER IF NOT EXISTS people_search_delete AFTER DELETE ON people BEGIN
+    DELETE FROM people_search WHERE id = old.id;
+END;
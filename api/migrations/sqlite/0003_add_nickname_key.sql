@@ -0,0 +1,10 @@
+-- NOCASE only folds ASCII letters, so `João` and `JOÃO` were told apart. The
+-- api now writes each nickname lowercased as a key, unique regardless of
+-- case like CITEXT. Existing rows are keyed by sqlite's lower(), which folds
+-- ASCII letters only like NOCASE did, so it can't fail on them, but they may
+-- still clash with nicknames differing from theirs in accented letters' case.
+ALTER TABLE people ADD COLUMN nickname_key TEXT;
+
+UPDATE people SET nickname_key = lower(nickname);
+
+CREATE UNIQUE INDEX IF NOT EXISTS people_nickname_key ON people (nickname_key);
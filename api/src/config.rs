@@ -0,0 +1,19 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+pub fn env_or<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(key) {
+        Ok(value) => value.parse().unwrap_or_else(|err| {
+            tracing::warn!(%err, "invalid value {value:?} for {key}, using default");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+pub fn env_duration_ms(key: &str, default: Duration) -> Duration {
+    Duration::from_millis(env_or(key, default.as_millis() as u64))
+}
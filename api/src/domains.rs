@@ -1,43 +1,112 @@
+mod fields;
+pub mod validation;
+
 use std::{collections::BTreeMap, hash::Hash};
 
-use anyhow::Result;
-use time::Date;
+use time::OffsetDateTime;
 use uuid::Uuid;
 
+pub use fields::{Birthday, InvalidField, Name, Nickname, StackItem};
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, sqlx::FromRow)]
 pub struct Person {
     pub id: Uuid,
     #[serde(alias = "name", rename = "nome")]
-    pub name: String,
+    pub name: Name,
     #[serde(alias = "nickname", rename = "apelido")]
-    pub nickname: String,
+    pub nickname: Nickname,
     #[serde(alias = "birthday", rename = "nascimento")]
-    pub birthday: Date,
-    pub stack: Option<Vec<String>>,
+    pub birthday: Birthday,
+    pub stack: Option<Vec<StackItem>>,
+    #[serde(skip, default = "OffsetDateTime::now_utc")]
+    pub updated_at: OffsetDateTime,
+    /// Bumped on every update, used for optimistic concurrency.
+    #[serde(skip, default = "initial_version")]
+    pub version: i32,
+}
+
+fn initial_version() -> i32 {
+    1
+}
+
+/// A person as sent to the api, before it is given an id. Built from
+/// [`validation::PersonFields`], which reports every invalid field at once;
+/// deserializing one directly flattens them into a serde error message.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(try_from = "validation::PersonFields")]
+pub struct NewPerson {
+    pub name: Name,
+    pub nickname: Nickname,
+    pub birthday: Birthday,
+    pub stack: Option<Vec<StackItem>>,
+}
+
+impl NewPerson {
+    pub fn into_person(self, id: Uuid) -> Person {
+        Person {
+            id,
+            name: self.name,
+            nickname: self.nickname,
+            birthday: self.birthday,
+            stack: self.stack,
+            updated_at: OffsetDateTime::now_utc(),
+            version: initial_version(),
+        }
+    }
+}
+
+/// A field of a person that failed validation, named as in the api payloads.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    /// Position of the offending item in list fields.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    #[serde(rename = "message")]
+    pub error: InvalidField,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, error: InvalidField) -> Self {
+        Self {
+            field,
+            index: None,
+            error,
+        }
+    }
+
+    pub fn at(field: &'static str, index: usize, error: InvalidField) -> Self {
+        Self {
+            index: Some(index),
+            ..Self::new(field, error)
+        }
+    }
 }
 
 impl Person {
-    pub fn validate(&self) -> Result<()> {
-        anyhow::ensure!(
-            self.nickname.len() <= 32,
-            "nickname must be at max 32 chars wide"
-        );
-        anyhow::ensure!(self.name.len() <= 100, "name must be at max 100 chars wide");
-
-        let all_valid = self
-            .stack
-            .as_ref()
-            .is_some_and(|s| s.iter().all(|s| s.len() <= 32));
-        anyhow::ensure!(all_valid, "stack names must be at max 32 chars wide");
-
-        Ok(())
+    /// Strong validator of the stored state, made of the version alone so
+    /// every replica hands out the same tag for it.
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.version)
+    }
+
+    /// Same as the `search_term` column: name, nickname and stack lowercased
+    /// and concatenated.
+    pub fn search_term(&self) -> String {
+        let mut term = self.name.to_lowercase();
+        term.push_str(&self.nickname.to_lowercase());
+        for item in self.stack.iter().flatten() {
+            term.push_str(&item.to_lowercase());
+        }
+
+        term
     }
 
     pub fn as_string_map(&self) -> BTreeMap<&'static str, String> {
         let mut map = BTreeMap::from([
             ("id", self.id.to_string()),
-            ("name", self.name.clone()),
-            ("nickname", self.nickname.clone()),
+            ("name", self.name.to_string()),
+            ("nickname", self.nickname.to_string()),
             ("birthday", self.birthday.to_string()),
         ]);
 
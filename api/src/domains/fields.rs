@@ -0,0 +1,256 @@
+//! Fields of a person that can only hold valid values, checked when they are
+//! built or deserialized. Values read from the database are trusted instead,
+//! as rows stored before these checks existed may not pass them.
+
+use std::{fmt, ops::Deref};
+
+use sqlx::{
+    database::{HasArguments, HasValueRef},
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgHasArrayType, PgTypeInfo},
+    Database, Decode, Encode, Type,
+};
+use time::{macros::format_description, Date, OffsetDateTime};
+
+/// Why a value can't be held by a field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidField {
+    #[error("is required")]
+    Missing,
+    #[error("can't be null")]
+    Null,
+    /// The value is of another json type, described like `a string`.
+    #[error("must be {0}")]
+    WrongType(&'static str),
+    #[error("can't be blank")]
+    Blank,
+    /// Lengths are counted in characters, not in bytes.
+    #[error("must be at most {0} characters long")]
+    TooLong(usize),
+    #[error("must be a date formatted as YYYY-MM-DD")]
+    MalformedDate,
+    #[error("is not a date that exists")]
+    NonexistentDate,
+    #[error("can't be in the future")]
+    FutureDate,
+}
+
+impl serde::Serialize for InvalidField {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+macro_rules! text_field {
+    ($(#[$doc:meta])* $name:ident, max_len = $max_len:expr) => {
+        $(#[$doc])*
+        #[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Deserialize)]
+        #[serde(try_from = "String")]
+        pub struct $name(String);
+
+        impl $name {
+            pub const MAX_LEN: usize = $max_len;
+
+            /// Rejects blank texts and those longer than [`Self::MAX_LEN`].
+            pub fn new(text: impl Into<String>) -> Result<Self, InvalidField> {
+                let text = text.into();
+                if text.trim().is_empty() {
+                    return Err(InvalidField::Blank);
+                }
+                if text.chars().count() > Self::MAX_LEN {
+                    return Err(InvalidField::TooLong(Self::MAX_LEN));
+                }
+
+                Ok(Self(text))
+            }
+
+            /// Takes `text` as it is, for values that were stored already.
+            pub(crate) fn new_unchecked(text: String) -> Self {
+                Self(text)
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = InvalidField;
+
+            fn try_from(text: String) -> Result<Self, Self::Error> {
+                Self::new(text)
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&self.0)
+            }
+        }
+
+        impl<DB: Database> Type<DB> for $name
+        where
+            String: Type<DB>,
+        {
+            fn type_info() -> DB::TypeInfo {
+                <String as Type<DB>>::type_info()
+            }
+
+            fn compatible(ty: &DB::TypeInfo) -> bool {
+                <String as Type<DB>>::compatible(ty)
+            }
+        }
+
+        impl<'q, DB: Database> Encode<'q, DB> for $name
+        where
+            String: Encode<'q, DB>,
+        {
+            fn encode_by_ref(&self, buf: &mut <DB as HasArguments<'q>>::ArgumentBuffer) -> IsNull {
+                self.0.encode_by_ref(buf)
+            }
+        }
+
+        impl<'r, DB: Database> Decode<'r, DB> for $name
+        where
+            String: Decode<'r, DB>,
+        {
+            fn decode(value: <DB as HasValueRef<'r>>::ValueRef) -> Result<Self, BoxDynError> {
+                Ok(Self::new_unchecked(String::decode(value)?))
+            }
+        }
+    };
+}
+
+text_field!(Name, max_len = 100);
+text_field!(Nickname, max_len = 32);
+text_field!(
+    /// A technology a person works with, like `rust`.
+    StackItem,
+    max_len = 32
+);
+
+impl PgHasArrayType for StackItem {
+    fn array_type_info() -> PgTypeInfo {
+        <String as PgHasArrayType>::array_type_info()
+    }
+
+    fn array_compatible(ty: &PgTypeInfo) -> bool {
+        <String as PgHasArrayType>::array_compatible(ty)
+    }
+}
+
+/// A date that already happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Birthday(Date);
+
+impl Birthday {
+    pub fn new(date: Date) -> Result<Self, InvalidField> {
+        if date > OffsetDateTime::now_utc().date() {
+            return Err(InvalidField::FutureDate);
+        }
+
+        Ok(Self(date))
+    }
+
+    /// Takes `date` as it is, for values that were stored already.
+    pub(crate) fn new_unchecked(date: Date) -> Self {
+        Self(date)
+    }
+
+    /// Parses a `YYYY-MM-DD` date.
+    pub fn parse(date: &str) -> Result<Self, InvalidField> {
+        let well_formed = date.len() == 10
+            && date.bytes().enumerate().all(|(i, b)| match i {
+                4 | 7 => b == b'-',
+                _ => b.is_ascii_digit(),
+            });
+        if !well_formed {
+            return Err(InvalidField::MalformedDate);
+        }
+
+        let date = Date::parse(date, format_description!("[year]-[month]-[day]"))
+            .map_err(|_| InvalidField::NonexistentDate)?;
+        Self::new(date)
+    }
+}
+
+impl TryFrom<String> for Birthday {
+    type Error = InvalidField;
+
+    fn try_from(date: String) -> Result<Self, Self::Error> {
+        Self::parse(&date)
+    }
+}
+
+impl Deref for Birthday {
+    type Target = Date;
+
+    fn deref(&self) -> &Date {
+        &self.0
+    }
+}
+
+impl fmt::Display for Birthday {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl serde::Serialize for Birthday {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
+impl<DB: Database> Type<DB> for Birthday
+where
+    Date: Type<DB>,
+{
+    fn type_info() -> DB::TypeInfo {
+        <Date as Type<DB>>::type_info()
+    }
+
+    fn compatible(ty: &DB::TypeInfo) -> bool {
+        <Date as Type<DB>>::compatible(ty)
+    }
+}
+
+impl<'q, DB: Database> Encode<'q, DB> for Birthday
+where
+    Date: Encode<'q, DB>,
+{
+    fn encode_by_ref(&self, buf: &mut <DB as HasArguments<'q>>::ArgumentBuffer) -> IsNull {
+        self.0.encode_by_ref(buf)
+    }
+}
+
+impl<'r, DB: Database> Decode<'r, DB> for Birthday
+where
+    Date: Decode<'r, DB>,
+{
+    fn decode(value: <DB as HasValueRef<'r>>::ValueRef) -> Result<Self, BoxDynError> {
+        Ok(Self::new_unchecked(Date::decode(value)?))
+    }
+}
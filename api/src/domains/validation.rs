@@ -0,0 +1,188 @@
+//! Checks of people as sent to the api. Fields are first read as any json
+//! value, so a value of the wrong type is reported on its field like any
+//! other mistake, and every field is checked before giving up.
+
+use serde_json::Value;
+
+use super::{Birthday, FieldError, InvalidField, NewPerson, StackItem};
+
+/// The fields of a person payload, as they were sent. Others are ignored.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct PersonFields {
+    #[serde(default)]
+    pub nome: Option<Value>,
+    #[serde(default)]
+    pub apelido: Option<Value>,
+    #[serde(default)]
+    pub nascimento: Option<Value>,
+    #[serde(default)]
+    pub stack: Option<Value>,
+}
+
+/// Everything that is wrong with a person payload.
+#[derive(Clone, Debug, thiserror::Error)]
+#[error("the person is invalid")]
+pub struct InvalidPerson(pub Vec<FieldError>);
+
+impl TryFrom<PersonFields> for NewPerson {
+    type Error = InvalidPerson;
+
+    fn try_from(fields: PersonFields) -> Result<Self, Self::Error> {
+        let mut errors = Vec::new();
+
+        let name = text("nome", fields.nome).map_err(|err| errors.push(err));
+        let nickname = text("apelido", fields.apelido).map_err(|err| errors.push(err));
+        let birthday = birthday(fields.nascimento).map_err(|err| errors.push(err));
+        let stack = stack(fields.stack).map_err(|errs| errors.extend(errs));
+
+        match (name, nickname, birthday, stack) {
+            (Ok(name), Ok(nickname), Ok(birthday), Ok(stack)) => Ok(Self {
+                name,
+                nickname,
+                birthday,
+                stack,
+            }),
+            _ => Err(InvalidPerson(errors)),
+        }
+    }
+}
+
+fn string(value: Option<Value>) -> Result<String, InvalidField> {
+    match value {
+        None | Some(Value::Null) => Err(InvalidField::Missing),
+        Some(Value::String(text)) => Ok(text),
+        Some(_) => Err(InvalidField::WrongType("a string")),
+    }
+}
+
+fn text<T: TryFrom<String, Error = InvalidField>>(
+    field: &'static str,
+    value: Option<Value>,
+) -> Result<T, FieldError> {
+    string(value)
+        .and_then(T::try_from)
+        .map_err(|err| FieldError::new(field, err))
+}
+
+fn birthday(value: Option<Value>) -> Result<Birthday, FieldError> {
+    string(value)
+        .and_then(|date| Birthday::parse(&date))
+        .map_err(|err| FieldError::new("nascimento", err))
+}
+
+fn stack(value: Option<Value>) -> Result<Option<Vec<StackItem>>, Vec<FieldError>> {
+    let items = match value {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::Array(items)) => items,
+        Some(_) => {
+            let err = InvalidField::WrongType("a list or null");
+            return Err(vec![FieldError::new("stack", err)]);
+        }
+    };
+
+    let mut stack = Vec::with_capacity(items.len());
+    let mut errors = Vec::new();
+    for (index, item) in items.into_iter().enumerate() {
+        let checked = match item {
+            Value::Null => Err(InvalidField::Null),
+            item => string(Some(item)).and_then(StackItem::new),
+        };
+
+        match checked {
+            Ok(item) => stack.push(item),
+            Err(err) => errors.push(FieldError::at("stack", index, err)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(Some(stack))
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn check(payload: Value) -> Result<NewPerson, InvalidPerson> {
+        NewPerson::try_from(serde_json::from_value::<PersonFields>(payload).unwrap())
+    }
+
+    #[test]
+    fn builds_typed_people() {
+        let person = check(json!({
+            "nome": "Ana",
+            "apelido": "ana",
+            "nascimento": "1985-09-23",
+            "stack": ["Rust"],
+            "outro": 1,
+        }))
+        .unwrap();
+        assert_eq!(person.name.as_str(), "Ana");
+        assert_eq!(person.birthday.to_string(), "1985-09-23");
+        assert_eq!(person.stack.unwrap()[0].as_str(), "Rust");
+    }
+
+    #[test]
+    fn reports_every_invalid_field() {
+        let InvalidPerson(errors) = check(json!({
+            "nome": 1,
+            "apelido": " ",
+            "nascimento": "2000-02-30",
+            "stack": ["Rust", null, "x".repeat(33)],
+        }))
+        .unwrap_err();
+
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|err| (err.field, err.index, err.error))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                ("nome", None, InvalidField::WrongType("a string")),
+                ("apelido", None, InvalidField::Blank),
+                ("nascimento", None, InvalidField::NonexistentDate),
+                ("stack", Some(1), InvalidField::Null),
+                ("stack", Some(2), InvalidField::TooLong(32)),
+            ]
+        );
+    }
+
+    #[test]
+    fn requires_fields_but_the_stack() {
+        let InvalidPerson(errors) = check(json!({ "stack": 1 })).unwrap_err();
+        let errors: Vec<_> = errors.iter().map(|err| (err.field, err.error)).collect();
+        assert_eq!(
+            errors,
+            [
+                ("nome", InvalidField::Missing),
+                ("apelido", InvalidField::Missing),
+                ("nascimento", InvalidField::Missing),
+                ("stack", InvalidField::WrongType("a list or null")),
+            ]
+        );
+    }
+
+    #[test]
+    fn deserializes_new_people_through_the_same_checks() {
+        let payload = json!({ "nome": "Ana", "apelido": "ana", "nascimento": "1985-09-23" });
+        assert!(serde_json::from_value::<NewPerson>(payload).is_ok());
+
+        let payload = json!({ "nome": "Ana", "apelido": "ana", "nascimento": "3000-01-01" });
+        let err = serde_json::from_value::<NewPerson>(payload).unwrap_err();
+        assert_eq!(err.to_string(), "the person is invalid");
+    }
+
+    #[test]
+    fn serializes_field_errors_with_their_message() {
+        let error = FieldError::at("stack", 1, InvalidField::TooLong(32));
+        assert_eq!(
+            serde_json::to_value(error).unwrap(),
+            json!({ "field": "stack", "index": 1, "message": "must be at most 32 characters long" })
+        );
+    }
+}
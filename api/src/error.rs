@@ -1,4 +1,12 @@
-use std::str::Utf8Error;
+use std::{str::Utf8Error, time::Duration};
+
+use http::{header::CONTENT_TYPE, HeaderValue, Method, StatusCode};
+
+use crate::{
+    domains::{validation::InvalidPerson, FieldError},
+    http::{IntoResponse, Json, Response},
+    repositories::DuplicateNickname,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ResponseError {
@@ -26,4 +34,277 @@ pub enum RequestError {
     InvalidHeaderEncoding(#[from] http::header::ToStrError),
     #[error("invalid content length value {0}")]
     InvalidContentLength(#[from] std::num::ParseIntError),
+    #[error("invalid chunk size {0}")]
+    InvalidChunkSize(std::num::ParseIntError),
+    #[error("missing content length")]
+    LengthRequired,
+    #[error("request body too large")]
+    PayloadTooLarge,
+    #[error("request line too long")]
+    UriTooLong,
+    #[error("request headers too large")]
+    HeadersTooLarge,
+}
+
+impl RequestError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::IoError(_)
+            | Self::InvalidEncoding(_)
+            | Self::InvalidFormat
+            | Self::HttpError(_)
+            | Self::InvalidHeaderEncoding(_)
+            | Self::InvalidContentLength(_)
+            | Self::InvalidChunkSize(_) => "malformed_request",
+            Self::LengthRequired => "length_required",
+            Self::PayloadTooLarge => "payload_too_large",
+            Self::UriTooLong => "uri_too_long",
+            Self::HeadersTooLarge => "headers_too_large",
+            Self::UnsupportedVersion => "unsupported_version",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::IoError(_)
+            | Self::InvalidEncoding(_)
+            | Self::InvalidFormat
+            | Self::HttpError(_)
+            | Self::InvalidHeaderEncoding(_)
+            | Self::InvalidContentLength(_)
+            | Self::InvalidChunkSize(_) => StatusCode::BAD_REQUEST,
+            Self::LengthRequired => StatusCode::LENGTH_REQUIRED,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UriTooLong => StatusCode::URI_TOO_LONG,
+            Self::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Self::UnsupportedVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+        }
+    }
+}
+
+impl IntoResponse for RequestError {
+    fn into_response(self) -> Response {
+        ApiError::from(self).into_response()
+    }
+}
+
+/// Errors surfaced by the api handlers, rendered as RFC 7807
+/// `application/problem+json` bodies carrying a stable `code`.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    Request(#[from] RequestError),
+    #[error("no route for {0} {1}")]
+    RouteNotFound(Method, String),
+    #[error("{0} is not allowed on {1}")]
+    MethodNotAllowed(Method, String),
+    #[error("the request took longer than {0:?}")]
+    Timeout(Duration),
+    #[error("the server is at capacity, try again later")]
+    Overloaded,
+    #[error("the request has no body")]
+    MissingBody,
+    #[error("the request body is not valid json: {0}")]
+    InvalidJson(#[source] serde_json::Error),
+    #[error("{0:?} is not a valid person id")]
+    InvalidId(String),
+    #[error("no person with id {0}")]
+    PersonNotFound(uuid::Uuid),
+    #[error("the search term `t` is required")]
+    MissingSearchTerm,
+    #[error("invalid value {1:?} for the query parameter `{0}`")]
+    InvalidQueryParam(&'static str, String),
+    #[error("the person is invalid")]
+    Validation(Vec<FieldError>),
+    #[error("this nickname is already registered")]
+    NicknameTaken,
+    #[error("a person with this id already exists")]
+    PersonExists,
+    #[error("the person was modified since it was last read")]
+    PreconditionFailed,
+    #[error("send the person's ETag in If-Match to change it")]
+    PreconditionRequired,
+    #[error("the person was modified concurrently, try again")]
+    EditConflict,
+    #[error("expected a {0} body")]
+    UnsupportedMediaType(&'static str),
+    #[error("can only respond with {0}")]
+    NotAcceptable(&'static str),
+    #[error("line {line} can't be imported: {detail}")]
+    InvalidImport {
+        line: usize,
+        detail: String,
+        errors: Vec<FieldError>,
+    },
+    #[error("imported {imported} people, then line {line} failed: {source}")]
+    ImportInterrupted {
+        imported: usize,
+        line: usize,
+        source: Box<ApiError>,
+    },
+    #[error("the database is unavailable")]
+    Unavailable(#[source] anyhow::Error),
+    #[error("unexpected error")]
+    Internal(#[source] anyhow::Error),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Request(err) => err.status(),
+            Self::RouteNotFound(..) => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed(..) => StatusCode::METHOD_NOT_ALLOWED,
+            Self::Timeout(_) | Self::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Self::MissingBody
+            | Self::InvalidJson(_)
+            | Self::MissingSearchTerm
+            | Self::InvalidQueryParam(..) => StatusCode::BAD_REQUEST,
+            Self::InvalidId(_)
+            | Self::Validation(_)
+            | Self::NicknameTaken
+            | Self::InvalidImport { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::ImportInterrupted { source, .. } => source.status(),
+            Self::PersonNotFound(_) => StatusCode::NOT_FOUND,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Self::PreconditionRequired => StatusCode::PRECONDITION_REQUIRED,
+            Self::EditConflict | Self::PersonExists => StatusCode::CONFLICT,
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Request(err) => err.code(),
+            Self::RouteNotFound(..) => "route_not_found",
+            Self::MethodNotAllowed(..) => "method_not_allowed",
+            Self::Timeout(_) => "timeout",
+            Self::Overloaded => "overloaded",
+            Self::MissingBody => "missing_body",
+            Self::InvalidJson(_) => "invalid_json",
+            Self::InvalidId(_) => "invalid_id",
+            Self::PersonNotFound(_) => "person_not_found",
+            Self::MissingSearchTerm => "missing_search_term",
+            Self::InvalidQueryParam(..) => "invalid_query_param",
+            Self::Validation(_) => "validation_failed",
+            Self::NicknameTaken => "nickname_taken",
+            Self::PersonExists => "person_exists",
+            Self::PreconditionFailed => "precondition_failed",
+            Self::PreconditionRequired => "precondition_required",
+            Self::EditConflict => "edit_conflict",
+            Self::UnsupportedMediaType(_) => "unsupported_media_type",
+            Self::NotAcceptable(_) => "not_acceptable",
+            Self::InvalidImport { .. } => "invalid_import",
+            Self::ImportInterrupted { .. } => "import_interrupted",
+            Self::Unavailable(_) => "repository_unavailable",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl From<FieldError> for ApiError {
+    fn from(value: FieldError) -> Self {
+        Self::Validation(vec![value])
+    }
+}
+
+impl From<InvalidPerson> for ApiError {
+    fn from(value: InvalidPerson) -> Self {
+        Self::Validation(value.0)
+    }
+}
+
+/// Classifies errors coming from a repository.
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        if err.is::<DuplicateNickname>() {
+            return Self::NicknameTaken;
+        }
+        // raised by stores other than the database, like the kv one
+        if err.is::<std::io::Error>() {
+            return Self::Unavailable(err);
+        }
+
+        let Some(sqlx_err) = err.downcast_ref::<sqlx::Error>() else {
+            return Self::Internal(err);
+        };
+
+        match sqlx_err {
+            sqlx::Error::Database(db) if db.is_unique_violation() => match is_id_conflict(&**db) {
+                true => Self::PersonExists,
+                false => Self::NicknameTaken,
+            },
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => {
+                Self::Unavailable(err)
+            }
+            _ => Self::Internal(err),
+        }
+    }
+}
+
+/// Whether a unique violation is about the primary key, as the only other
+/// unique column is the nickname. Postgres names the constraint, while
+/// sqlite only names the column in its message.
+fn is_id_conflict(err: &dyn sqlx::error::DatabaseError) -> bool {
+    match err.constraint() {
+        Some(constraint) => constraint == "people_pkey",
+        None => err.message().ends_with("people.id"),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    kind: String,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    errors: &'a [FieldError],
+    /// Where an import stopped, and how many people it stored before.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    imported: Option<usize>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        // timeouts and shed requests are expected under load, and are
+        // logged where they happen
+        if status.is_server_error() && !matches!(self, Self::Timeout(_) | Self::Overloaded) {
+            let source = std::error::Error::source(&self).map(|err| format!("{err:#}"));
+            tracing::error!(code = self.code(), ?source, "{self}");
+        }
+
+        let errors = match &self {
+            Self::Validation(errors) | Self::InvalidImport { errors, .. } => errors.as_slice(),
+            _ => &[],
+        };
+        let (line, imported) = match self {
+            Self::InvalidImport { line, .. } => (Some(line), Some(0)),
+            Self::ImportInterrupted { imported, line, .. } => (Some(line), Some(imported)),
+            _ => (None, None),
+        };
+        let problem = Problem {
+            kind: format!("/problems/{}", self.code().replace('_', "-")),
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            code: self.code(),
+            errors,
+            line,
+            imported,
+        };
+
+        let mut response = (status, Json(problem)).into_response();
+        const PROBLEM_JSON: HeaderValue = HeaderValue::from_static("application/problem+json");
+        response.headers_mut().insert(CONTENT_TYPE, PROBLEM_JSON);
+
+        response
+    }
 }
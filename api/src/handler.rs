@@ -1,146 +1,1087 @@
-use std::array::from_ref;
+use std::{array::from_ref, collections::HashSet};
 
-use http::{Method, Response as Resp, StatusCode};
+use http::{
+    header::{CONTENT_TYPE, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LINK},
+    HeaderValue, Method, Response as Resp, StatusCode,
+};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use time::{format_description::well_known::Iso8601, Date, OffsetDateTime};
 use uuid::Uuid;
 
 use crate::{
-    domains::Person,
-    http::{IntoResponse, Json, Request, Response},
+    config,
+    domains::{validation::PersonFields, FieldError, NewPerson, Person},
+    error::ApiError,
+    http::{
+        date,
+        router::{PathParams, Router, TrailingSlash},
+        Body, IntoResponse, Json, Request, Response,
+    },
+    repositories::{Cursor, SearchMode, SearchQuery, SearchSort, Written},
     AppState,
 };
 
-use self::payload::NewPerson;
-
-pub async fn route_request(request: Request, app_state: AppState) -> Response {
-    macro_rules! routes {
-        (
-            $($m:ident $p:literal $($v:ident)? => $f:expr),*
-            $(, _ => $wc:expr)?
-        ) => {
-            $(if request.method() == Method::$m {
-                let path = request.uri().path()
-                    .strip_prefix($p)
-                    .map(|s| s.strip_suffix("/").unwrap_or(s));
-                if let Some(_path) = path {
-                    $(let $v = _path;)?
-                    return $f;
-                }
-            })*
-            $(return $wc;)?
+mod transfer;
 
-        };
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(Method::GET, "/pessoas/:id", get_person)
+        .route(Method::PUT, "/pessoas/:id", replace_person)
+        .route(Method::PATCH, "/pessoas/:id", patch_person)
+        .route(Method::DELETE, "/pessoas/:id", delete_person)
+        .route(Method::GET, "/pessoas", search_people)
+        .route(Method::POST, "/pessoas", create_person)
+        .route(Method::POST, "/pessoas/lote", create_people)
+        .route(Method::GET, "/pessoas/export", transfer::export_people)
+        .route(Method::POST, "/pessoas/import", transfer::import_people)
+        .route(Method::GET, "/contagem-pessoas", count_people)
+        .trailing_slash(config::env_or("TRAILING_SLASH", TrailingSlash::Ignore))
+}
+
+fn person_id(request: &Request) -> Result<Uuid, ApiError> {
+    let id = request
+        .extensions()
+        .get::<PathParams>()
+        .and_then(|params| params.get("id"))
+        .unwrap_or_default();
+
+    id.parse().map_err(|_| ApiError::InvalidId(id.to_string()))
+}
+
+async fn get_person(request: Request, app_state: AppState) -> Result<Response, ApiError> {
+    let id = person_id(&request)?;
+
+    let person = app_state
+        .repository
+        .find_one(id)
+        .await?
+        .ok_or(ApiError::PersonNotFound(id))?;
+
+    if is_fresh(&request, &person.etag(), person.updated_at) {
+        let response = Resp::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::Empty)
+            .unwrap();
+        return Ok(with_validators(response, &person));
     }
 
-    routes!(
-        GET "/pessoas/" id => get_person(app_state, id).await,
-        GET "/pessoas" => search_people(app_state, request).await,
-        POST "/pessoas" => create_person(app_state, request).await,
-        GET "/contagem-pessoas" => count_people(app_state).await,
-        _ => {
-            let msg = format!(
-                "Unknown route {} {}",
-                request.method(),
-                request.uri().path()
-            );
-            (StatusCode::NOT_FOUND, msg).into_response()
-        }
+    Ok(person_response(&person))
+}
+
+fn person_response(person: &Person) -> Response {
+    with_validators((StatusCode::OK, Json(person)).into_response(), person)
+}
+
+fn with_validators(mut response: Response, person: &Person) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(ETAG, HeaderValue::from_str(&person.etag()).unwrap());
+    headers.insert(
+        LAST_MODIFIED,
+        HeaderValue::from_str(&date::format(person.updated_at)).unwrap(),
     );
+
+    response
 }
 
-async fn get_person(app_state: AppState, id: &str) -> Response {
-    let Ok(id): Result<Uuid, _> = id.parse() else {
-        return (StatusCode::UNPROCESSABLE_ENTITY, "invalid id").into_response();
+/// Whether the client already holds the current representation, following
+/// RFC 9110's precedence of `If-None-Match` over `If-Modified-Since`.
+fn is_fresh(request: &Request, etag: &str, updated_at: OffsetDateTime) -> bool {
+    let headers = request.headers();
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+
+    headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(date::parse)
+        .is_some_and(|since| updated_at.unix_timestamp() <= since.unix_timestamp())
+}
+
+/// Whether writes must carry `If-Match`, answering `428 Precondition Required`
+/// to those that don't.
+static REQUIRE_IF_MATCH: Lazy<bool> = Lazy::new(|| config::env_or("REQUIRE_IF_MATCH", false));
+
+/// The versions listed by `If-Match`, `None` when any will do, either because
+/// it is `*` or, unless `required`, missing. Weak tags never match, and a list
+/// without any other tag fails the precondition outright. A missing person
+/// fails any `If-Match`, see [`not_found`].
+fn if_match_versions(request: &Request, required: bool) -> Result<Option<Vec<i32>>, ApiError> {
+    let Some(if_match) = request.headers().get(IF_MATCH) else {
+        return match required {
+            true => Err(ApiError::PreconditionRequired),
+            false => Ok(None),
+        };
     };
 
-    let person = app_state
+    let if_match = if_match
+        .to_str()
+        .map_err(|_| ApiError::PreconditionFailed)?;
+    if if_match.trim() == "*" {
+        return Ok(None);
+    }
+
+    let versions: Vec<i32> = if_match
+        .split(',')
+        .filter_map(|tag| {
+            tag.trim()
+                .strip_prefix('"')?
+                .strip_suffix('"')?
+                .parse()
+                .ok()
+        })
+        .collect();
+    match versions.is_empty() {
+        true => Err(ApiError::PreconditionFailed),
+        false => Ok(Some(versions)),
+    }
+}
+
+/// The version a write of person `id` must find, out of those `If-Match`
+/// lists. With more than one, the stored person decides which.
+async fn expected_version(
+    request: &Request,
+    id: Uuid,
+    app_state: &AppState,
+) -> Result<Option<i32>, ApiError> {
+    let Some(versions) = if_match_versions(request, *REQUIRE_IF_MATCH)? else {
+        return Ok(None);
+    };
+    if let [version] = versions[..] {
+        return Ok(Some(version));
+    }
+
+    let current = app_state
         .repository
         .find_one(id)
-        .await
-        .expect("failed to get person");
+        .await?
+        .ok_or(ApiError::PreconditionFailed)?;
+    match versions.contains(&current.version) {
+        true => Ok(Some(current.version)),
+        false => Err(ApiError::PreconditionFailed),
+    }
+}
 
-    (StatusCode::OK, Json(person)).into_response()
+/// The error for a missing person, which per RFC 9110 fails the precondition
+/// of requests with `If-Match`, even `*`.
+fn not_found(id: Uuid, if_match: bool) -> ApiError {
+    match if_match {
+        true => ApiError::PreconditionFailed,
+        false => ApiError::PersonNotFound(id),
+    }
 }
 
-async fn search_people(app_state: AppState, request: Request) -> Response {
-    let Some(term) = request.uri().query().and_then(|q| q.strip_prefix("t=")) else {
-        return (StatusCode::BAD_REQUEST, "").into_response();
-    };
+async fn replace_person(request: Request, app_state: AppState) -> Result<Response, ApiError> {
+    let id = person_id(&request)?;
+    let expected = expected_version(&request, id, &app_state).await?;
+    let if_match = request.headers().contains_key(IF_MATCH);
+
+    let body = request.into_body().ok_or(ApiError::MissingBody)?;
+    let mut person = person_from(id, &body)?;
+
+    match app_state.repository.update_one(&person, expected).await? {
+        Written::Applied { version } => person.version = version,
+        Written::NotFound => return Err(not_found(id, if_match)),
+        Written::VersionMismatch => return Err(ApiError::PreconditionFailed),
+    }
+
+    Ok(person_response(&person))
+}
+
+/// Applies a JSON merge patch (RFC 7396) on top of the stored person.
+async fn patch_person(request: Request, app_state: AppState) -> Result<Response, ApiError> {
+    const MERGE_PATCH: &str = "application/merge-patch+json";
+
+    let id = person_id(&request)?;
+    let expected = if_match_versions(&request, *REQUIRE_IF_MATCH)?;
+    let if_match = request.headers().contains_key(IF_MATCH);
+
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim);
+    if !matches!(content_type, Some(MERGE_PATCH | "application/json")) {
+        return Err(ApiError::UnsupportedMediaType(MERGE_PATCH));
+    }
+
+    let body = request.into_body().ok_or(ApiError::MissingBody)?;
+    let patch: Value = serde_json::from_slice(&body).map_err(ApiError::InvalidJson)?;
 
-    let people = app_state
+    let current = app_state
         .repository
-        .search_many(term)
-        .await
-        .expect("failed to search people");
+        .find_one(id)
+        .await?
+        .ok_or_else(|| not_found(id, if_match))?;
+    if expected
+        .as_ref()
+        .is_some_and(|versions| !versions.contains(&current.version))
+    {
+        return Err(ApiError::PreconditionFailed);
+    }
 
-    (StatusCode::OK, Json(people)).into_response()
+    let mut target = serde_json::to_value(&current).map_err(anyhow::Error::from)?;
+    merge_patch(&mut target, patch);
+    let fields: PersonFields = serde_json::from_value(target).map_err(ApiError::InvalidJson)?;
+    let mut person = NewPerson::try_from(fields)?.into_person(id);
+
+    // the patch was computed against `current`, so it must still be there
+    match app_state
+        .repository
+        .update_one(&person, Some(current.version))
+        .await?
+    {
+        Written::Applied { version } => person.version = version,
+        Written::NotFound => return Err(not_found(id, if_match)),
+        Written::VersionMismatch if expected.is_some() => return Err(ApiError::PreconditionFailed),
+        Written::VersionMismatch => return Err(ApiError::EditConflict),
+    }
+
+    Ok(person_response(&person))
 }
 
-async fn create_person(app_state: AppState, request: Request) -> Response {
-    let body = request.into_body().expect("failed to read body");
-    let Ok(person): Result<NewPerson , _> = serde_json::from_slice(&body) else {
-        return (StatusCode::BAD_REQUEST, "invalid json").into_response();
+/// The person with `id` out of a json object body.
+fn person_from(id: Uuid, body: &[u8]) -> Result<Person, ApiError> {
+    let fields: PersonFields = serde_json::from_slice(body).map_err(ApiError::InvalidJson)?;
+    Ok(NewPerson::try_from(fields)?.into_person(id))
+}
+
+fn merge_patch(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let Value::Object(target) = target else {
+        unreachable!()
     };
-    let person: Person = person.into();
 
-    if let Err(err) = person.validate() {
-        return (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()).into_response();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge_patch(target.entry(key).or_insert(Value::Null), value);
+        }
+    }
+}
+
+async fn delete_person(request: Request, app_state: AppState) -> Result<Response, ApiError> {
+    let id = person_id(&request)?;
+    let expected = expected_version(&request, id, &app_state).await?;
+    let if_match = request.headers().contains_key(IF_MATCH);
+
+    match app_state.repository.delete_one(id, expected).await? {
+        Written::Applied { .. } => Ok(StatusCode::NO_CONTENT.into_response()),
+        Written::NotFound => Err(not_found(id, if_match)),
+        Written::VersionMismatch => Err(ApiError::PreconditionFailed),
     }
+}
 
-    let err = app_state.repository.insert_many(from_ref(&person)).await;
-    if let Err(err) = err {
-        let is_unique_violation = err
-            .downcast_ref::<sqlx::Error>()
-            .and_then(|err| err.as_database_error())
-            .is_some_and(|err| err.is_unique_violation());
+/// Similarity required by ranked searches when `min_score` is not given.
+static MIN_SCORE: Lazy<f32> = Lazy::new(|| config::env_or("SEARCH_MIN_SCORE", 0.3));
+
+/// People per page of a search when `limit` is not given.
+const SEARCH_LIMIT: usize = 50;
+const MAX_SEARCH_LIMIT: usize = 500;
+
+/// Finds people by the term `t`, contained in their fields as written or,
+/// with `mode=fulltext`, word by word regardless of accents. Only postgres
+/// stems portuguese words there; without it, words are matched as prefixes,
+/// so `desenvolvedor` finds `desenvolvedora` but not the other way around.
+/// They can be narrowed down to those with every `stack` given and born
+/// between `nascimento_de` and `nascimento_ate`.
+///
+/// Pages of `limit` people are sorted by `sort`, one of `criacao`,
+/// `-criacao`, `nascimento` and `-nascimento`, and the next one is linked
+/// with a `cursor`. With `rank=true`, the best matches come first in a single
+/// page regardless of `sort`, people only similar to the term are found too
+/// outside of full-text mode, and `score=true` adds the relevance of each of
+/// them, from 0 to 1, to the response.
+async fn search_people(request: Request, app_state: AppState) -> Result<Response, ApiError> {
+    let params: Vec<(String, String)> =
+        form_urlencoded::parse(request.uri().query().unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect();
+
+    let mut query = SearchQuery {
+        term: String::new(),
+        mode: SearchMode::default(),
+        ranked: false,
+        min_score: *MIN_SCORE,
+        stack: Vec::new(),
+        born_from: None,
+        born_until: None,
+        sort: SearchSort::default(),
+        after: None,
+        limit: SEARCH_LIMIT,
+    };
+    let (mut term, mut with_score) = (None, false);
+    for (key, value) in &params {
+        let invalid = |name| ApiError::InvalidQueryParam(name, value.clone());
+        match key.as_str() {
+            "t" => term = Some(value.clone()),
+            "rank" => query.ranked = value.parse().map_err(|_| invalid("rank"))?,
+            "min_score" => {
+                query.min_score = value
+                    .parse()
+                    .ok()
+                    .filter(|score| (0.0..=1.0).contains(score))
+                    .ok_or_else(|| invalid("min_score"))?
+            }
+            "score" => with_score = value.parse().map_err(|_| invalid("score"))?,
+            "mode" => {
+                query.mode = match value.as_str() {
+                    "trigram" => SearchMode::Trigram,
+                    "fulltext" => SearchMode::FullText,
+                    _ => return Err(invalid("mode")),
+                }
+            }
+            "stack" => query.stack.push(value.to_lowercase()),
+            "nascimento_de" => {
+                let from = Date::parse(value, &Iso8601::DATE);
+                query.born_from = Some(from.map_err(|_| invalid("nascimento_de"))?);
+            }
+            "nascimento_ate" => {
+                let until = Date::parse(value, &Iso8601::DATE);
+                query.born_until = Some(until.map_err(|_| invalid("nascimento_ate"))?);
+            }
+            "sort" => {
+                query.sort = match value.as_str() {
+                    "criacao" => SearchSort::Oldest,
+                    "-criacao" => SearchSort::Newest,
+                    "nascimento" => SearchSort::Eldest,
+                    "-nascimento" => SearchSort::Youngest,
+                    _ => return Err(invalid("sort")),
+                }
+            }
+            "cursor" => query.after = Some(value.parse().map_err(|_| invalid("cursor"))?),
+            "limit" => {
+                query.limit = value
+                    .parse()
+                    .ok()
+                    .filter(|limit| (1..=MAX_SEARCH_LIMIT).contains(limit))
+                    .ok_or_else(|| invalid("limit"))?
+            }
+            _ => {}
+        }
+    }
+    query.term = term.ok_or(ApiError::MissingSearchTerm)?;
 
-        if is_unique_violation {
-            return (
-                StatusCode::UNPROCESSABLE_ENTITY,
-                "this nickname is already registered",
-            )
-                .into_response();
+    // ranked pages can't be resumed, as scores don't make for a stable key
+    if let Some(after) = query.after {
+        if query.ranked || after.birthday.is_some() != query.sort.by_birthday() {
+            return Err(ApiError::InvalidQueryParam("cursor", after.to_string()));
         }
     }
 
-    Resp::builder()
+    // one more than asked for tells whether there is a next page
+    let limit = query.limit;
+    query.limit += 1;
+    let mut hits = app_state.repository.search_many(&query).await?;
+    let next = (hits.len() > limit && !query.ranked)
+        .then(|| Cursor::new(&hits[limit - 1].person, query.sort));
+    hits.truncate(limit);
+    if !with_score {
+        hits.iter_mut().for_each(|hit| hit.score = None);
+    }
+
+    let mut response = (StatusCode::OK, Json(hits)).into_response();
+    if let Some(next) = next {
+        let link = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params.iter().filter(|(key, _)| key != "cursor"))
+            .append_pair("cursor", &next.to_string())
+            .finish();
+        let headers = response.headers_mut();
+        headers.insert(
+            LINK,
+            HeaderValue::from_str(&format!("<{}?{link}>; rel=\"next\"", request.uri().path()))
+                .unwrap(),
+        );
+        headers.insert(
+            "x-next-cursor",
+            HeaderValue::from_str(&next.to_string()).unwrap(),
+        );
+    }
+
+    Ok(response)
+}
+
+async fn create_person(request: Request, app_state: AppState) -> Result<Response, ApiError> {
+    let body = request.into_body().ok_or(ApiError::MissingBody)?;
+    let person = person_from(Uuid::now_v7(), &body)?;
+
+    app_state.repository.insert_many(from_ref(&person)).await?;
+
+    Ok(Resp::builder()
         .status(StatusCode::CREATED)
         .header("Location", format!("/pessoas/{}", person.id))
-        .body(None)
-        .unwrap()
+        .body(Body::Empty)
+        .unwrap())
 }
 
-async fn count_people(app_state: AppState) -> Response {
-    let rows = app_state
-        .repository
-        .count_people()
-        .await
-        .expect("failed to count people");
+/// How many people go into each multi-row `INSERT` of a bulk creation.
+const BULK_CHUNK_SIZE: usize = 1000;
+
+/// Creates many people at once from a JSON array, or from one JSON object per
+/// line when sent as `application/x-ndjson`. Every entry gets its own result,
+/// in the order they were sent. Chunks stored before one fails stay stored,
+/// that one's entries are marked as failed and later ones are not attempted.
+async fn create_people(request: Request, app_state: AppState) -> Result<Response, ApiError> {
+    let ndjson = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-ndjson"));
+    let body = request.into_body().ok_or(ApiError::MissingBody)?;
+
+    let entries: Vec<serde_json::Result<PersonFields>> = if ndjson {
+        body.split(|&b| b == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .map(serde_json::from_slice)
+            .collect()
+    } else {
+        serde_json::from_slice::<Vec<Value>>(&body)
+            .map_err(ApiError::InvalidJson)?
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect()
+    };
+
+    let mut results = Vec::with_capacity(entries.len());
+    let (mut pending, mut indexes) = (Vec::new(), Vec::new());
+    for entry in entries {
+        let fields = match entry {
+            Ok(fields) => fields,
+            Err(err) => {
+                results.push(BulkResult::InvalidJson {
+                    detail: err.to_string(),
+                });
+                continue;
+            }
+        };
+
+        match NewPerson::try_from(fields) {
+            Ok(person) => {
+                indexes.push(results.len());
+                pending.push(person.into_person(Uuid::now_v7()));
+                results.push(BulkResult::NotCreated);
+            }
+            Err(invalid) => results.push(BulkResult::Invalid { errors: invalid.0 }),
+        }
+    }
+
+    for (people, indexes) in pending
+        .chunks(BULK_CHUNK_SIZE)
+        .zip(indexes.chunks(BULK_CHUNK_SIZE))
+    {
+        let inserted: HashSet<_> = match app_state.repository.try_insert_many(people).await {
+            Ok(inserted) => inserted.into_iter().collect(),
+            Err(err) => {
+                let err = ApiError::from(err);
+                let source = std::error::Error::source(&err).map(|err| format!("{err:#}"));
+                tracing::error!(code = err.code(), ?source, "bulk creation failed");
+                for &index in indexes {
+                    results[index] = BulkResult::Failed {
+                        code: err.code(),
+                        detail: err.to_string(),
+                    };
+                }
+                break;
+            }
+        };
 
-    (StatusCode::OK, rows.to_string()).into_response()
+        for (person, &index) in people.iter().zip(indexes) {
+            results[index] = match inserted.contains(&person.id) {
+                true => BulkResult::Created { id: person.id },
+                false => BulkResult::DuplicateNickname,
+            };
+        }
+    }
+
+    Ok((StatusCode::OK, Json(results)).into_response())
+}
+
+/// Outcome of a single entry of [`create_people`].
+#[derive(serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum BulkResult {
+    Created {
+        id: Uuid,
+    },
+    InvalidJson {
+        detail: String,
+    },
+    Invalid {
+        errors: Vec<FieldError>,
+    },
+    DuplicateNickname,
+    /// The chunk holding it could not be stored.
+    Failed {
+        code: &'static str,
+        detail: String,
+    },
+    /// Not attempted, as an earlier chunk failed.
+    NotCreated,
 }
 
-mod payload {
-    use time::Date;
+async fn count_people(_: Request, app_state: AppState) -> Result<Response, ApiError> {
+    let rows = app_state.repository.count_people().await?;
+
+    Ok((StatusCode::OK, rows.to_string()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use bytes::Bytes;
+    use serde_json::json;
+
+    use crate::{
+        http::Handler,
+        repositories::{
+            memory::InMemoryPeopleRepository, PeopleRepository, PeopleStream, SearchHit,
+        },
+    };
 
     use super::*;
 
-    #[derive(Debug, serde::Deserialize, serde::Serialize)]
-    pub(super) struct NewPerson {
-        pub nome: String,
-        pub apelido: String,
-        pub nascimento: Date,
-        pub stack: Option<Vec<String>>,
+    struct App {
+        router: Router<AppState>,
+        state: AppState,
     }
 
-    impl From<NewPerson> for Person {
-        fn from(value: NewPerson) -> Self {
+    impl App {
+        fn new() -> Self {
+            Self::with(InMemoryPeopleRepository::default())
+        }
+
+        fn with(repository: impl PeopleRepository + Send + Sync + 'static) -> Self {
             Self {
-                id: Uuid::now_v7(),
-                name: value.nome,
-                nickname: value.apelido,
-                birthday: value.nascimento,
-                stack: value.stack,
+                router: router(),
+                state: AppState {
+                    repository: Arc::new(repository),
+                },
+            }
+        }
+
+        async fn send(&self, method: Method, uri: &str, body: Option<Value>) -> Response {
+            let body = body.map(|body| Bytes::from(body.to_string()));
+            let request = http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body)
+                .unwrap();
+
+            self.call(request).await
+        }
+
+        async fn call(&self, request: Request) -> Response {
+            self.router.call(request, self.state.clone()).await
+        }
+
+        /// Creates a person, returning where it can be found.
+        async fn create(&self, person: Value) -> String {
+            let response = self.send(Method::POST, "/pessoas", Some(person)).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+            response.headers()["location"].to_str().unwrap().to_string()
+        }
+    }
+
+    fn json_body(response: &Response) -> Value {
+        serde_json::from_slice(response.body().as_bytes().unwrap()).unwrap()
+    }
+
+    fn ana() -> Value {
+        json!({
+            "apelido": "ana",
+            "nome": "Ana Barbosa",
+            "nascimento": "1985-09-23",
+            "stack": ["Rust", "Postgres"],
+        })
+    }
+
+    #[tokio::test]
+    async fn creates_and_gets_people() {
+        let app = App::new();
+        let location = app.create(ana()).await;
+
+        let response = app.send(Method::GET, &location, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ETAG], "\"1\"");
+        let person = json_body(&response);
+        assert_eq!(person["apelido"], "ana");
+        assert_eq!(person["nome"], "Ana Barbosa");
+        assert_eq!(person["nascimento"], "1985-09-23");
+        assert_eq!(person["stack"], json!(["Rust", "Postgres"]));
+        assert_eq!(
+            location,
+            format!("/pessoas/{}", person["id"].as_str().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_missing_and_invalid_ids() {
+        let app = App::new();
+
+        let uri = format!("/pessoas/{}", Uuid::now_v7());
+        let response = app.send(Method::GET, &uri, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json_body(&response)["code"], "person_not_found");
+
+        let response = app.send(Method::GET, "/pessoas/nope", None).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(json_body(&response)["code"], "invalid_id");
+    }
+
+    #[tokio::test]
+    async fn rejects_taken_nicknames() {
+        let app = App::new();
+        app.create(ana()).await;
+
+        let mut other = ana();
+        other["apelido"] = json!("ANA");
+        let response = app.send(Method::POST, "/pessoas", Some(other)).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(json_body(&response)["code"], "nickname_taken");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_people() {
+        let app = App::new();
+        let mut person = ana();
+        person["nome"] = json!(" ");
+
+        let response = app.send(Method::POST, "/pessoas", Some(person)).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(json_body(&response)["code"], "validation_failed");
+    }
+
+    #[tokio::test]
+    async fn searches_people() {
+        let app = App::new();
+        app.create(ana()).await;
+        let mut other = ana();
+        other["apelido"] = json!("bia");
+        other["nome"] = json!("Beatriz");
+        other["stack"] = json!(["Go"]);
+        app.create(other).await;
+
+        let response = app.send(Method::GET, "/pessoas?t=postgres", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let hits = json_body(&response);
+        assert_eq!(hits.as_array().unwrap().len(), 1);
+        assert_eq!(hits[0]["apelido"], "ana");
+
+        let response = app.send(Method::GET, "/pessoas?t=zzz", None).await;
+        assert_eq!(json_body(&response), json!([]));
+
+        let response = app.send(Method::GET, "/pessoas", None).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(&response)["code"], "missing_search_term");
+    }
+
+    #[tokio::test]
+    async fn pages_searches_with_cursors() {
+        let app = App::new();
+        for nickname in ["ana", "bia", "cris"] {
+            let mut person = ana();
+            person["apelido"] = json!(nickname);
+            app.create(person).await;
+        }
+
+        let response = app.send(Method::GET, "/pessoas?t=rust&limit=2", None).await;
+        let first = json_body(&response);
+        assert_eq!(first.as_array().unwrap().len(), 2);
+        let cursor = response.headers()["x-next-cursor"].to_str().unwrap();
+
+        let uri = format!("/pessoas?t=rust&limit=2&cursor={cursor}");
+        let response = app.send(Method::GET, &uri, None).await;
+        let second = json_body(&response);
+        let mut nicknames: Vec<_> = first
+            .as_array()
+            .unwrap()
+            .iter()
+            .chain(second.as_array().unwrap())
+            .map(|hit| hit["apelido"].as_str().unwrap())
+            .collect();
+        nicknames.sort();
+        assert_eq!(nicknames, ["ana", "bia", "cris"]);
+        assert!(response.headers().get("x-next-cursor").is_none());
+    }
+
+    fn get(uri: &str) -> http::request::Builder {
+        http::Request::builder().method(Method::GET).uri(uri)
+    }
+
+    #[tokio::test]
+    async fn sends_validators_and_honors_conditional_gets() {
+        let app = App::new();
+        let location = app.create(ana()).await;
+
+        let response = app.send(Method::GET, &location, None).await;
+        let last_modified = response.headers()[LAST_MODIFIED].to_str().unwrap();
+        assert!(date::parse(last_modified).is_some());
+
+        for if_none_match in ["\"1\"", "W/\"1\"", "\"7\", \"1\"", "*"] {
+            let request = get(&location).header(IF_NONE_MATCH, if_none_match);
+            let response = app.call(request.body(None).unwrap()).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::NOT_MODIFIED,
+                "{if_none_match}"
+            );
+            assert_eq!(response.headers()[ETAG], "\"1\"");
+            assert!(matches!(response.body(), Body::Empty));
+        }
+
+        let request = get(&location).header(IF_NONE_MATCH, "\"2\"");
+        let response = app.call(request.body(None).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn honors_if_modified_since_unless_if_none_match_is_sent() {
+        let app = App::new();
+        let location = app.create(ana()).await;
+        let response = app.send(Method::GET, &location, None).await;
+        let last_modified = response.headers()[LAST_MODIFIED].clone();
+
+        let request = get(&location).header(IF_MODIFIED_SINCE, last_modified.clone());
+        let response = app.call(request.body(None).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let request = get(&location).header(IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT");
+        let response = app.call(request.body(None).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // If-None-Match takes precedence
+        let request = get(&location)
+            .header(IF_MODIFIED_SINCE, last_modified)
+            .header(IF_NONE_MATCH, "\"2\"");
+        let response = app.call(request.body(None).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn write(method: Method, uri: &str, if_match: Option<&str>, body: Value) -> Request {
+        let mut request = http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(if_match) = if_match {
+            request = request.header(IF_MATCH, if_match);
+        }
+
+        request.body(Some(Bytes::from(body.to_string()))).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replaces_people_bumping_their_version() {
+        let app = App::new();
+        let location = app.create(ana()).await;
+        let mut person = ana();
+        person["nome"] = json!("Ana Souza");
+
+        let request = write(Method::PUT, &location, Some("\"1\""), person.clone());
+        let response = app.call(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ETAG], "\"2\"");
+        assert_eq!(json_body(&response)["nome"], "Ana Souza");
+
+        // the tag the client holds is stale now
+        let request = write(Method::PUT, &location, Some("\"1\""), person.clone());
+        let response = app.call(request).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(json_body(&response)["code"], "precondition_failed");
+
+        let response = app.send(Method::PUT, &location, Some(person)).await;
+        assert_eq!(response.headers()[ETAG], "\"3\"");
+
+        let response = app.send(Method::GET, &location, None).await;
+        assert_eq!(response.headers()[ETAG], "\"3\"");
+        assert_eq!(json_body(&response)["nome"], "Ana Souza");
+    }
+
+    #[tokio::test]
+    async fn merge_patches_people() {
+        let app = App::new();
+        let location = app.create(ana()).await;
+
+        let patch = json!({"nome": "Ana Souza", "stack": null});
+        let mut request = write(Method::PATCH, &location, Some("\"1\""), patch.clone());
+        request.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/merge-patch+json"),
+        );
+        let response = app.call(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ETAG], "\"2\"");
+        let person = json_body(&response);
+        assert_eq!(person["nome"], "Ana Souza");
+        assert_eq!(person["apelido"], "ana");
+        assert_eq!(person["stack"], Value::Null);
+
+        let request = write(Method::PATCH, &location, Some("\"1\""), patch.clone());
+        let response = app.call(request).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let mut request = write(Method::PATCH, &location, None, patch);
+        request
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        let response = app.call(request).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn deletes_people() {
+        let app = App::new();
+        let location = app.create(ana()).await;
+
+        let mut request = write(Method::DELETE, &location, Some("\"2\""), Value::Null);
+        *request.body_mut() = None;
+        let response = app.call(request).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let response = app.send(Method::DELETE, &location, None).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app.send(Method::GET, &location, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app.send(Method::DELETE, &location, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn fails_preconditions_on_missing_people() {
+        let app = App::new();
+        let uri = format!("/pessoas/{}", Uuid::now_v7());
+
+        let response = app.send(Method::PUT, &uri, Some(ana())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        for if_match in ["*", "\"1\"", "\"1\", \"2\""] {
+            for method in [Method::PUT, Method::PATCH, Method::DELETE] {
+                let request = write(method.clone(), &uri, Some(if_match), ana());
+                let response = app.call(request).await;
+                let status = response.status();
+                assert_eq!(
+                    status,
+                    StatusCode::PRECONDITION_FAILED,
+                    "{method} {if_match}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn matches_any_tag_of_if_match_lists() {
+        let app = App::new();
+        let location = app.create(ana()).await;
+
+        for if_match in ["W/\"1\"", "\"5\", \"6\"", "\"one\""] {
+            let request = write(Method::PUT, &location, Some(if_match), ana());
+            let response = app.call(request).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::PRECONDITION_FAILED,
+                "{if_match}"
+            );
+        }
+
+        let request = write(Method::PUT, &location, Some("\"5\", W/\"1\", \"1\""), ana());
+        let response = app.call(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ETAG], "\"2\"");
+    }
+
+    #[test]
+    fn requires_if_match_when_asked_to() {
+        let request = write(Method::PUT, "/pessoas/1", None, ana());
+        let err = if_match_versions(&request, true).unwrap_err();
+        assert_eq!(err.status(), StatusCode::PRECONDITION_REQUIRED);
+        assert_eq!(err.code(), "precondition_required");
+        assert!(if_match_versions(&request, false).unwrap().is_none());
+
+        let request = write(Method::PUT, "/pessoas/1", Some("*"), ana());
+        assert!(if_match_versions(&request, true).unwrap().is_none());
+    }
+
+    /// Misbehaves like a busy or failing database would.
+    #[derive(Default)]
+    struct Faulty {
+        inner: InMemoryPeopleRepository,
+        /// Changes every person it finds behind the back of the caller, as
+        /// a concurrent request would.
+        racing: bool,
+        /// Bulk inserts that succeed before every other one fails.
+        bulk_inserts: Option<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl PeopleRepository for Faulty {
+        async fn find_one(&self, id: Uuid) -> anyhow::Result<Option<Person>> {
+            let person = self.inner.find_one(id).await?;
+            if let Some(person) = person.as_ref().filter(|_| self.racing) {
+                self.inner.update_one(person, None).await?;
+            }
+            Ok(person)
+        }
+
+        async fn search_many(&self, query: &SearchQuery) -> anyhow::Result<Vec<SearchHit>> {
+            self.inner.search_many(query).await
+        }
+
+        async fn insert_many(&self, people: &[Person]) -> anyhow::Result<()> {
+            self.inner.insert_many(people).await
+        }
+
+        async fn try_insert_many(&self, people: &[Person]) -> anyhow::Result<Vec<Uuid>> {
+            let left = self.bulk_inserts.as_ref().map(|left| {
+                left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            });
+            if let Some(Err(_)) = left {
+                let err = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "gone");
+                return Err(err.into());
             }
+            self.inner.try_insert_many(people).await
+        }
+
+        async fn count_people(&self) -> anyhow::Result<i64> {
+            self.inner.count_people().await
+        }
+
+        async fn list_nicknames(&self) -> anyhow::Result<Vec<(String, Uuid)>> {
+            self.inner.list_nicknames().await
+        }
+
+        async fn update_one(
+            &self,
+            person: &Person,
+            expected_version: Option<i32>,
+        ) -> anyhow::Result<Written> {
+            self.inner.update_one(person, expected_version).await
+        }
+
+        async fn delete_one(
+            &self,
+            id: Uuid,
+            expected_version: Option<i32>,
+        ) -> anyhow::Result<Written> {
+            self.inner.delete_one(id, expected_version).await
+        }
+
+        async fn stream_all(&self) -> anyhow::Result<PeopleStream> {
+            self.inner.stream_all().await
         }
     }
+
+    #[tokio::test]
+    async fn reports_concurrent_patches() {
+        let app = App::with(Faulty {
+            racing: true,
+            ..Default::default()
+        });
+        let location = app.create(ana()).await;
+        let patch = json!({"nome": "Ana Souza"});
+
+        let request = write(Method::PATCH, &location, None, patch.clone());
+        let response = app.call(request).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(json_body(&response)["code"], "edit_conflict");
+
+        // the person matches If-Match when read, but not by the time it is
+        // written
+        let request = write(Method::PATCH, &location, Some("\"2\""), patch);
+        let response = app.call(request).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    fn people(nicknames: impl IntoIterator<Item = String>) -> Value {
+        let people = nicknames.into_iter().map(|nickname| {
+            let mut person = ana();
+            person["apelido"] = json!(nickname);
+            person
+        });
+        Value::Array(people.collect())
+    }
+
+    #[tokio::test]
+    async fn reports_each_bulk_entry() {
+        let app = App::new();
+        app.create(ana()).await;
+
+        let mut entries = people(["bia", "ana", "bia"].map(String::from));
+        let entries_mut = entries.as_array_mut().unwrap();
+        entries_mut.push(json!({"apelido": "cris"}));
+        entries_mut.push(json!(42));
+        let response = app.send(Method::POST, "/pessoas/lote", Some(entries)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let results = json_body(&response);
+        let statuses: Vec<_> = results
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                "created",
+                "duplicate_nickname",
+                "duplicate_nickname",
+                "invalid",
+                "invalid_json"
+            ]
+        );
+        let location = format!("/pessoas/{}", results[0]["id"].as_str().unwrap());
+        let response = app.send(Method::GET, &location, None).await;
+        assert_eq!(json_body(&response)["apelido"], "bia");
+    }
+
+    #[tokio::test]
+    async fn keeps_bulk_chunks_stored_before_one_fails() {
+        let app = App::with(Faulty {
+            bulk_inserts: Some(AtomicUsize::new(1)),
+            ..Default::default()
+        });
+        let count = 2 * BULK_CHUNK_SIZE + 1;
+        let entries = people((0..count).map(|i| format!("dev{i}")));
+
+        let response = app.send(Method::POST, "/pessoas/lote", Some(entries)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let results = json_body(&response);
+        let results = results.as_array().unwrap();
+        let (stored, rest) = results.split_at(BULK_CHUNK_SIZE);
+        let (failed, skipped) = rest.split_at(BULK_CHUNK_SIZE);
+        assert!(stored.iter().all(|result| result["status"] == "created"));
+        assert!(failed.iter().all(
+            |result| result["status"] == "failed" && result["code"] == "repository_unavailable"
+        ));
+        assert_eq!(skipped, [json!({"status": "not_created"})]);
+
+        let response = app.send(Method::GET, "/contagem-pessoas", None).await;
+        assert_eq!(
+            response.body().as_bytes().unwrap(),
+            BULK_CHUNK_SIZE.to_string().as_bytes()
+        );
+    }
 }
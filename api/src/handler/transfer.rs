@@ -0,0 +1,477 @@
+use std::{array::from_ref, io};
+
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use http::{
+    header::{ACCEPT, CONTENT_TYPE},
+    Method, Response as Resp, StatusCode,
+};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    domains::{validation::PersonFields, NewPerson, Person},
+    error::ApiError,
+    http::{Body, IntoResponse, Json, Request, Response},
+    AppState,
+};
+
+use super::BULK_CHUNK_SIZE;
+
+const NDJSON: &str = "application/x-ndjson";
+const CSV: &str = "text/csv";
+const CSV_HEADER: &[u8] = b"id,nome,apelido,nascimento,stack\n";
+
+/// Formats people are exported and imported in.
+#[derive(Clone, Copy)]
+enum Format {
+    Ndjson,
+    Csv,
+}
+
+impl Format {
+    fn media_type(self) -> &'static str {
+        match self {
+            Self::Ndjson => NDJSON,
+            Self::Csv => CSV,
+        }
+    }
+
+    /// The format preferred by the `Accept` header of `request`, NDJSON when
+    /// it has none.
+    fn negotiate(request: &Request) -> Result<Self, ApiError> {
+        let Some(accept) = request.headers().get(ACCEPT) else {
+            return Ok(Self::Ndjson);
+        };
+        let accept = accept.to_str().unwrap_or_default();
+
+        let mut ranges: Vec<(String, f32)> = accept
+            .split(',')
+            .map(|range| {
+                let mut params = range.split(';').map(str::trim);
+                let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+                let quality = params
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|quality| quality.parse().ok())
+                    .unwrap_or(1.0);
+                (media_type, quality)
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+        // stable, so equally preferred types keep their order
+        ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        ranges
+            .iter()
+            .find_map(|(media_type, _)| match media_type.as_str() {
+                NDJSON | "application/*" | "*/*" => Some(Self::Ndjson),
+                CSV | "text/*" => Some(Self::Csv),
+                _ => None,
+            })
+            .ok_or(ApiError::NotAcceptable("application/x-ndjson or text/csv"))
+    }
+
+    /// The format of the body of `request`, NDJSON unless told otherwise.
+    fn of_body(request: &Request) -> Result<Self, ApiError> {
+        let content_type = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase());
+
+        match content_type.as_deref() {
+            None | Some(NDJSON) => Ok(Self::Ndjson),
+            Some(CSV) => Ok(Self::Csv),
+            Some(_) => Err(ApiError::UnsupportedMediaType(
+                "application/x-ndjson or text/csv",
+            )),
+        }
+    }
+
+    fn encode(self, people: &[Person]) -> io::Result<Bytes> {
+        match self {
+            Self::Ndjson => {
+                let mut buf = Vec::new();
+                for person in people {
+                    serde_json::to_writer(&mut buf, person)?;
+                    buf.push(b'\n');
+                }
+                Ok(buf.into())
+            }
+            Self::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(Vec::new());
+                for person in people {
+                    writer.serialize(CsvRecord::from(person))?;
+                }
+                let buf = writer.into_inner().map_err(|err| err.into_error())?;
+                Ok(buf.into())
+            }
+        }
+    }
+}
+
+/// A person as a CSV record, with the stack as a JSON array, left empty when
+/// there is none. Fields are kept as text to be checked like json ones.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct CsvRecord {
+    id: Option<String>,
+    nome: String,
+    apelido: String,
+    nascimento: String,
+    stack: String,
+}
+
+impl From<&Person> for CsvRecord {
+    fn from(person: &Person) -> Self {
+        Self {
+            id: Some(person.id.to_string()),
+            nome: person.name.to_string(),
+            apelido: person.nickname.to_string(),
+            nascimento: person.birthday.to_string(),
+            stack: person
+                .stack
+                .as_ref()
+                .map(|stack| serde_json::to_string(stack).unwrap())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Streams every person, oldest first, as NDJSON or as CSV when preferred by
+/// `Accept`. The response starts before everyone was read, so a failure
+/// midway cuts it short instead of turning into an error response.
+pub(super) async fn export_people(
+    request: Request,
+    app_state: AppState,
+) -> Result<Response, ApiError> {
+    let format = Format::negotiate(&request)?;
+    let response = Resp::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, format.media_type());
+    // the body would only be thrown away, but it is still a streamed one
+    if request.method() == Method::HEAD {
+        return Ok(response.body(Body::stream(stream::empty())).unwrap());
+    }
+
+    let people = app_state.repository.stream_all().await?;
+    let header = match format {
+        Format::Csv => Some(Ok(Bytes::from_static(CSV_HEADER))),
+        Format::Ndjson => None,
+    };
+    let body = stream::iter(header).chain(people.map(move |batch| match batch {
+        Ok(batch) => format.encode(&batch),
+        Err(err) => {
+            tracing::error!(%err, "failed to export people");
+            Err(io::Error::other(err))
+        }
+    }));
+
+    Ok(response.body(Body::stream(body)).unwrap())
+}
+
+/// Creates the people of an export, sent as NDJSON or as `text/csv`, keeping
+/// their ids when given. Every line is checked before anyone is stored, then
+/// people are inserted in order a batch at a time. When one can't be, like on
+/// a taken nickname, everyone before its line stays in, and the error tells
+/// how many they are and which line failed.
+pub(super) async fn import_people(
+    request: Request,
+    app_state: AppState,
+) -> Result<Response, ApiError> {
+    let format = Format::of_body(&request)?;
+    let body = request.into_body().ok_or(ApiError::MissingBody)?;
+    let (lines, people): (Vec<_>, Vec<_>) = match format {
+        Format::Ndjson => read_ndjson(&body)?,
+        Format::Csv => read_csv(&body)?,
+    }
+    .into_iter()
+    .unzip();
+
+    let mut imported = 0;
+    for batch in people.chunks(BULK_CHUNK_SIZE) {
+        if app_state.repository.insert_many(batch).await.is_ok() {
+            imported += batch.len();
+            continue;
+        }
+
+        // one by one, to store everyone up to the culprit and find its line
+        for person in batch {
+            if let Err(err) = app_state.repository.insert_many(from_ref(person)).await {
+                return Err(ApiError::ImportInterrupted {
+                    imported,
+                    line: lines[imported],
+                    source: Box::new(err.into()),
+                });
+            }
+            imported += 1;
+        }
+    }
+
+    let imported = serde_json::json!({ "imported": imported });
+    Ok((StatusCode::OK, Json(imported)).into_response())
+}
+
+fn invalid_line(line: usize, detail: impl ToString) -> ApiError {
+    ApiError::InvalidImport {
+        line,
+        detail: detail.to_string(),
+        errors: Vec::new(),
+    }
+}
+
+/// A person to import, with the id it was exported with, if any.
+#[derive(serde::Deserialize)]
+struct ImportFields {
+    #[serde(default)]
+    id: Option<Value>,
+    #[serde(flatten)]
+    person: PersonFields,
+}
+
+fn checked(line: usize, fields: ImportFields) -> Result<Person, ApiError> {
+    let id = match fields.id {
+        None | Some(Value::Null) => Uuid::now_v7(),
+        Some(id) => id
+            .as_str()
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| invalid_line(line, "`id` is not a valid uuid"))?,
+    };
+
+    match NewPerson::try_from(fields.person) {
+        Ok(person) => Ok(person.into_person(id)),
+        Err(invalid) => Err(ApiError::InvalidImport {
+            line,
+            detail: invalid.to_string(),
+            errors: invalid.0,
+        }),
+    }
+}
+
+/// The people of an NDJSON body, each with the line it was on.
+fn read_ndjson(body: &[u8]) -> Result<Vec<(usize, Person)>, ApiError> {
+    let mut people = Vec::new();
+    for (index, line) in body.split(|&b| b == b'\n').enumerate() {
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+
+        let fields = serde_json::from_slice(line).map_err(|err| invalid_line(index + 1, err))?;
+        people.push((index + 1, checked(index + 1, fields)?));
+    }
+
+    Ok(people)
+}
+
+/// The people of a CSV body, each with the line its record starts on.
+fn read_csv(body: &[u8]) -> Result<Vec<(usize, Person)>, ApiError> {
+    let mut reader = csv::Reader::from_reader(body);
+    let headers = reader
+        .headers()
+        .map_err(|err| invalid_line(1, err))?
+        .clone();
+
+    let mut people = Vec::new();
+    let mut record = csv::StringRecord::new();
+    loop {
+        let start = reader.position().clone();
+        let line = match reader.read_record(&mut record) {
+            Ok(true) => record_line(body, record.position().unwrap_or(&start)),
+            Ok(false) => break,
+            Err(err) => {
+                let line = record_line(body, err.position().unwrap_or(&start));
+                return Err(invalid_line(line, err));
+            }
+        };
+
+        let row: CsvRecord = record
+            .deserialize(Some(&headers))
+            .map_err(|err| invalid_line(line, err))?;
+        let stack = match row.stack.as_str() {
+            "" => Value::Null,
+            stack => serde_json::from_str(stack).map_err(|err| invalid_line(line, err))?,
+        };
+        let fields = ImportFields {
+            id: row.id.map(Value::String),
+            person: PersonFields {
+                nome: Some(Value::String(row.nome)),
+                apelido: Some(Value::String(row.apelido)),
+                nascimento: Some(Value::String(row.nascimento)),
+                stack: Some(stack),
+            },
+        };
+        people.push((line, checked(line, fields)?));
+    }
+
+    Ok(people)
+}
+
+/// The line a record starts on, as csv counts from before the blank lines
+/// it skips.
+fn record_line(body: &[u8], position: &csv::Position) -> usize {
+    let start = usize::try_from(position.byte()).map_or(body.len(), |start| start.min(body.len()));
+    let skipped = body[start..]
+        .iter()
+        .take_while(|&&b| b == b'\n' || b == b'\r')
+        .filter(|&&b| b == b'\n')
+        .count();
+
+    position.line() as usize + skipped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+
+    use crate::{
+        handler::router,
+        http::Handler,
+        repositories::{memory::InMemoryPeopleRepository, PeopleRepository},
+    };
+
+    use super::*;
+
+    const PEOPLE: &str = r#"{"apelido":"ana","nome":"Ana, \"a\" Barbosa","nascimento":"1985-09-23","stack":["Rust","C#"]}
+
+{"id":"01a14da4-425b-77f5-92c1-117f0c1fb867","apelido":"bia","nome":"Beatriz\nSouza","nascimento":"2000-01-01","stack":null}
+"#;
+
+    fn request(method: Method, uri: &str, accept: Option<&str>, body: Option<&str>) -> Request {
+        let mut request = http::Request::builder().method(method).uri(uri);
+        if let Some(accept) = accept {
+            request = request.header(ACCEPT, accept);
+        }
+        request
+            .body(body.map(|body| Bytes::from(body.to_string())))
+            .unwrap()
+    }
+
+    fn negotiate(accept: &str) -> Result<Format, ApiError> {
+        Format::negotiate(&request(Method::GET, "/", Some(accept), None))
+    }
+
+    fn strings(people: &[(usize, Person)]) -> Vec<(usize, BTreeMap<&'static str, String>)> {
+        people
+            .iter()
+            .map(|(line, person)| (*line, person.as_string_map()))
+            .collect()
+    }
+
+    #[test]
+    fn negotiates_export_formats() {
+        let no_accept = request(Method::GET, "/", None, None);
+        assert!(matches!(Format::negotiate(&no_accept), Ok(Format::Ndjson)));
+        assert!(matches!(negotiate("text/csv"), Ok(Format::Csv)));
+        assert!(matches!(negotiate("TEXT/*"), Ok(Format::Csv)));
+        assert!(matches!(negotiate("*/*"), Ok(Format::Ndjson)));
+        assert!(matches!(
+            negotiate("text/csv;q=0.5, application/x-ndjson"),
+            Ok(Format::Ndjson)
+        ));
+        assert!(matches!(
+            negotiate("application/json, text/csv; q=0.1"),
+            Ok(Format::Csv)
+        ));
+        assert!(matches!(
+            negotiate("application/json, text/csv;q=0"),
+            Err(ApiError::NotAcceptable(_))
+        ));
+    }
+
+    #[test]
+    fn round_trips_people() {
+        let people = read_ndjson(PEOPLE.as_bytes()).unwrap();
+        assert_eq!(
+            people.iter().map(|(line, _)| *line).collect::<Vec<_>>(),
+            [1, 3]
+        );
+        let (_, people): (Vec<_>, Vec<_>) = people.into_iter().unzip();
+
+        let ndjson = Format::Ndjson.encode(&people).unwrap();
+        let from_ndjson = read_ndjson(&ndjson).unwrap();
+
+        let mut csv = CSV_HEADER.to_vec();
+        csv.extend_from_slice(&Format::Csv.encode(&people).unwrap());
+        let from_csv = read_csv(&csv).unwrap();
+
+        let expected: Vec<_> = people.iter().map(Person::as_string_map).collect();
+        for read in [from_ndjson, from_csv] {
+            let read: Vec<_> = strings(&read)
+                .into_iter()
+                .map(|(_, fields)| fields)
+                .collect();
+            assert_eq!(read, expected);
+        }
+    }
+
+    #[test]
+    fn reads_csv_with_the_line_records_start_on() {
+        let csv = "\
+id,nome,apelido,nascimento,stack
+,Ana,ana,1985-09-23,\"[\"\"Rust\"\"]\"
+,\"Beatriz
+Souza\",bia,2000-01-01,
+,Cris,cris,2000-01-01,
+";
+        let people = read_csv(csv.as_bytes()).unwrap();
+        let lines: Vec<_> = people.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [2, 3, 5]);
+    }
+
+    #[test]
+    fn reports_the_line_of_invalid_csv_records() {
+        let invalid_person =
+            "id,nome,apelido,nascimento,stack\n,Ana,ana,1985-09-23,\n, ,bia,2000-01-01,\n";
+        let Err(ApiError::InvalidImport { line, errors, .. }) = read_csv(invalid_person.as_bytes())
+        else {
+            panic!("expected an invalid import");
+        };
+        assert_eq!(line, 3);
+        assert_eq!(errors[0].field, "nome");
+
+        let invalid_stack = "id,nome,apelido,nascimento,stack\n\n,Ana,ana,1985-09-23,[\n";
+        let Err(ApiError::InvalidImport { line, .. }) = read_csv(invalid_stack.as_bytes()) else {
+            panic!("expected an invalid import");
+        };
+        assert_eq!(line, 3);
+    }
+
+    #[tokio::test]
+    async fn tells_how_many_people_were_imported_before_a_failure() {
+        let repository = Arc::new(InMemoryPeopleRepository::default());
+        let taken = read_ndjson(br#"{"apelido":"BIA","nome":"B","nascimento":"2000-01-01"}"#)
+            .unwrap()
+            .remove(0)
+            .1;
+        repository.insert_many(&[taken]).await.unwrap();
+        let state = AppState { repository };
+
+        let import = request(Method::POST, "/pessoas/import", None, Some(PEOPLE));
+        let response = router().call(import, state.clone()).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let problem: Value = serde_json::from_slice(response.body().as_bytes().unwrap()).unwrap();
+        assert_eq!(problem["code"], "import_interrupted");
+        assert_eq!(problem["imported"], 1);
+        assert_eq!(problem["line"], 3);
+        assert_eq!(state.repository.count_people().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn answers_head_exports_without_a_body() {
+        let state = AppState {
+            repository: Arc::new(InMemoryPeopleRepository::default()),
+        };
+
+        let head = request(Method::HEAD, "/pessoas/export", Some("text/csv"), None);
+        let response = router().call(head, state).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], CSV);
+        assert_eq!(response.body().len(), Some(0));
+        assert_eq!(response.headers()[TRANSFER_ENCODING], "chunked");
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+    }
+}
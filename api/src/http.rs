@@ -1,8 +1,19 @@
 pub const LINE_DELIMITER: &[u8] = b"\r\n";
 pub const REQUEST_DELIMITER: &[u8] = b"\r\n\r\n";
 
+pub const CHUNKED: http::HeaderValue = http::HeaderValue::from_static("chunked");
+pub const CLOSE: http::HeaderValue = http::HeaderValue::from_static("close");
+pub const KEEP_ALIVE: http::HeaderValue = http::HeaderValue::from_static("keep-alive");
+
+mod body;
 pub mod codec;
+pub mod date;
+mod handler;
+pub mod middleware;
 mod response;
+pub mod router;
 
 pub type Request = http::Request<Option<bytes::Bytes>>;
+pub use body::{Body, Chunk};
+pub use handler::Handler;
 pub use response::{IntoResponse, Json, Response};
@@ -0,0 +1,81 @@
+use std::{fmt, io, pin::Pin};
+
+use bytes::Bytes;
+use futures_util::Stream;
+
+pub type BodyStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// Response body, either fully buffered or produced chunk by chunk when its
+/// length is not known up front.
+#[derive(Default)]
+pub enum Body {
+    #[default]
+    Empty,
+    Full(Bytes),
+    Stream(BodyStream),
+}
+
+impl Body {
+    pub fn stream<S>(stream: S) -> Self
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        Self::Stream(Box::pin(stream))
+    }
+
+    pub fn as_bytes(&self) -> Option<&Bytes> {
+        match self {
+            Self::Full(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Length of a buffered body, `None` if it is streamed.
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            Self::Empty => Some(0),
+            Self::Full(bytes) => Some(bytes.len()),
+            Self::Stream(_) => None,
+        }
+    }
+
+    /// Takes the stream out of a streamed body, leaving an empty one behind
+    /// so the body still reads as having no known length.
+    pub fn take_stream(&mut self) -> Option<BodyStream> {
+        match self {
+            Self::Stream(stream) => Some(std::mem::replace(
+                stream,
+                Box::pin(futures_util::stream::empty()),
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(value: Bytes) -> Self {
+        Self::Full(value)
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(value: Vec<u8>) -> Self {
+        Self::Full(value.into())
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("Empty"),
+            Self::Full(bytes) => f.debug_tuple("Full").field(bytes).finish(),
+            Self::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+/// A piece of a `Transfer-Encoding: chunked` body.
+pub enum Chunk {
+    Data(Bytes),
+    End,
+}
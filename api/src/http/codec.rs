@@ -1,7 +1,17 @@
-use std::{fmt::Write, str::from_utf8};
+use std::{
+    fmt::Write,
+    str::{from_utf8, FromStr},
+};
 
-use bytes::Buf;
-use http::{header::CONTENT_LENGTH, request::Builder, Error as HttpError, Method, Uri, Version};
+use bytes::{Buf, BytesMut};
+use http::{
+    header::{
+        HeaderName, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST,
+        PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+    },
+    request::Builder,
+    Error as HttpError, HeaderMap, Method, StatusCode, Uri, Version,
+};
 use memchr::memmem;
 use once_cell::sync::Lazy;
 use tokio_util::codec::{Decoder, Encoder};
@@ -11,13 +21,72 @@ use crate::{
     http::{LINE_DELIMITER, REQUEST_DELIMITER},
 };
 
-use super::{Request, Response};
+use super::{Chunk, Request, Response};
 
 static FINDER: Lazy<memmem::Finder> = Lazy::new(|| memmem::Finder::new(LINE_DELIMITER));
 
+/// Bounds enforced while reading a request, so a single client cannot make
+/// the decoder buffer arbitrary amounts of memory.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_request_line_len: usize,
+    /// Size of the whole request head, request line included.
+    pub max_headers_len: usize,
+    pub max_headers: usize,
+    pub max_body_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_request_line_len: 8 * 1024,
+            max_headers_len: 16 * 1024,
+            max_headers: 64,
+            max_body_len: 1024 * 1024,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct ConnectionCodec {
-    pub req: Option<(Builder, usize)>,
+    limits: Limits,
+    req: Option<(Builder, BodyKind)>,
+    /// Bytes of the buffered head already searched for the delimiter.
+    scanned: usize,
+    /// Whether the last response written announced a chunked body, otherwise
+    /// its chunks are written as they are and the body ends with the
+    /// connection.
+    chunked: bool,
+}
+
+impl ConnectionCodec {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+}
+
+enum BodyKind {
+    Length(usize),
+    Chunked(ChunkedBody),
+}
+
+#[derive(Default)]
+struct ChunkedBody {
+    body: BytesMut,
+    state: ChunkState,
+    trailers: usize,
+}
+
+#[derive(Default)]
+enum ChunkState {
+    #[default]
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
 }
 
 impl Decoder for ConnectionCodec {
@@ -26,49 +95,249 @@ impl Decoder for ConnectionCodec {
     type Error = RequestError;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let (req, len) = match self.req.take() {
+        let (mut req, kind) = match self.req.take() {
             Some(req) => req,
             None => {
-                let Some(position) = memmem::find(&src, REQUEST_DELIMITER) else {
+                let Some(position) = self.find_head_end(src)? else {
                     return Ok(None);
                 };
 
                 let req = src.split_to(position);
-                let req = request_from_slice(&req)?;
+                let req = request_from_slice(&req, &self.limits)?;
                 src.advance(REQUEST_DELIMITER.len());
 
-                let Some(content_length) = req.headers_ref().and_then(|map| map.get(CONTENT_LENGTH)) else {
-                    return req.body(None).map(Some).map_err(RequestError::HttpError);
-                };
+                let kind = body_kind(&req, &self.limits)?;
+                match kind {
+                    Some(kind) => (req, kind),
+                    None => return req.body(None).map(Some).map_err(RequestError::HttpError),
+                }
+            }
+        };
+
+        let body = match kind {
+            BodyKind::Length(len) => {
+                if src.len() < len {
+                    src.reserve(len - src.len());
+                    self.req = Some((req, BodyKind::Length(len)));
+                    return Ok(None);
+                }
 
-                let content_length = content_length.to_str()?.parse::<usize>()?;
+                // anything past the body belongs to the next pipelined request
+                src.split_to(len).freeze()
+            }
+            BodyKind::Chunked(mut chunked) => {
+                if !decode_chunked(&mut chunked, &mut req, src, &self.limits)? {
+                    self.req = Some((req, BodyKind::Chunked(chunked)));
+                    return Ok(None);
+                }
 
-                (req, content_length)
+                chunked.body.freeze()
             }
         };
 
-        if src.len() < len {
-            src.reserve(len);
-            return Ok(None);
+        req.body(Some(body))
+            .map(Some)
+            .map_err(RequestError::HttpError)
+    }
+}
+
+impl ConnectionCodec {
+    /// Looks for the end of the request head, only searching bytes that
+    /// arrived since the last call and failing as soon as the buffered head
+    /// outgrows the limits.
+    fn find_head_end(&mut self, src: &BytesMut) -> Result<Option<usize>, RequestError> {
+        let start = self.scanned.saturating_sub(REQUEST_DELIMITER.len() - 1);
+        let end = src
+            .len()
+            .min(self.limits.max_headers_len + REQUEST_DELIMITER.len());
+
+        if let Some(position) = memmem::find(&src[start..end], REQUEST_DELIMITER) {
+            self.scanned = 0;
+            return Ok(Some(start + position));
         }
+        self.scanned = end;
 
-        assert_eq!(
-            src.len(),
-            len,
-            "client sent a body larger than reported ({} > {len})",
-            src.len()
-        );
+        let line_end = src
+            .len()
+            .min(self.limits.max_request_line_len + LINE_DELIMITER.len());
+        if src.len() > self.limits.max_request_line_len && FINDER.find(&src[..line_end]).is_none() {
+            return Err(RequestError::UriTooLong);
+        }
 
-        req.body(Some(src.split().freeze()))
-            .map(Some)
-            .map_err(RequestError::HttpError)
+        if src.len() >= self.limits.max_headers_len + REQUEST_DELIMITER.len() {
+            return Err(RequestError::HeadersTooLarge);
+        }
+
+        Ok(None)
     }
 }
 
+/// Decides how the body of a request is delimited, rejecting ambiguous
+/// framing and body-carrying methods that do not state a length.
+fn body_kind(req: &Builder, limits: &Limits) -> Result<Option<BodyKind>, RequestError> {
+    let Some(headers) = req.headers_ref() else {
+        return Ok(None);
+    };
+
+    let content_length = headers.get(CONTENT_LENGTH);
+    if headers.contains_key(TRANSFER_ENCODING) {
+        if content_length.is_some() || !is_chunked(headers) {
+            return Err(RequestError::InvalidFormat);
+        }
+
+        return Ok(Some(BodyKind::Chunked(ChunkedBody::default())));
+    }
+
+    if let Some(content_length) = content_length {
+        let content_length = content_length.to_str()?.parse::<usize>()?;
+        if content_length > limits.max_body_len {
+            return Err(RequestError::PayloadTooLarge);
+        }
+
+        return Ok(Some(BodyKind::Length(content_length)));
+    }
+
+    let method = req.method_ref();
+    if method.is_some_and(|m| [Method::POST, Method::PUT, Method::PATCH].contains(m)) {
+        return Err(RequestError::LengthRequired);
+    }
+
+    Ok(None)
+}
+
+/// Advances the chunked body state machine with whatever is buffered in `src`,
+/// returning `true` once the last chunk and the trailer section were consumed.
+/// Chunk extensions are ignored and trailer fields are appended to the
+/// request headers, except for those that only make sense in the head, see
+/// [`is_trailer_allowed`].
+fn decode_chunked(
+    chunked: &mut ChunkedBody,
+    req: &mut Builder,
+    src: &mut BytesMut,
+    limits: &Limits,
+) -> Result<bool, RequestError> {
+    loop {
+        match chunked.state {
+            ChunkState::Size => {
+                let Some(position) = FINDER.find(src) else {
+                    return match src.len() > limits.max_headers_len {
+                        true => Err(RequestError::HeadersTooLarge),
+                        false => Ok(false),
+                    };
+                };
+
+                let line = src.split_to(position);
+                src.advance(LINE_DELIMITER.len());
+
+                let line = from_utf8(&line)?;
+                let size = line.split_once(';').map_or(line, |(size, _)| size).trim();
+                let size =
+                    usize::from_str_radix(size, 16).map_err(RequestError::InvalidChunkSize)?;
+
+                if size > limits.max_body_len - chunked.body.len() {
+                    return Err(RequestError::PayloadTooLarge);
+                }
+
+                chunked.state = match size {
+                    0 => ChunkState::Trailers,
+                    size => ChunkState::Data(size),
+                };
+            }
+            ChunkState::Data(remaining) => {
+                if src.is_empty() {
+                    return Ok(false);
+                }
+
+                let read = remaining.min(src.len());
+                chunked.body.extend_from_slice(&src.split_to(read));
+
+                chunked.state = match remaining - read {
+                    0 => ChunkState::DataEnd,
+                    remaining => ChunkState::Data(remaining),
+                };
+            }
+            ChunkState::DataEnd => {
+                if src.len() < LINE_DELIMITER.len() {
+                    return Ok(false);
+                }
+
+                if !src.starts_with(LINE_DELIMITER) {
+                    return Err(RequestError::InvalidFormat);
+                }
+
+                src.advance(LINE_DELIMITER.len());
+                chunked.state = ChunkState::Size;
+            }
+            ChunkState::Trailers => {
+                let Some(position) = FINDER.find(src) else {
+                    return match src.len() > limits.max_headers_len {
+                        true => Err(RequestError::HeadersTooLarge),
+                        false => Ok(false),
+                    };
+                };
+
+                let line = src.split_to(position);
+                src.advance(LINE_DELIMITER.len());
+
+                if line.is_empty() {
+                    return Ok(true);
+                }
+
+                chunked.trailers += 1;
+                if chunked.trailers > limits.max_headers {
+                    return Err(RequestError::HeadersTooLarge);
+                }
+
+                let mut line = from_utf8(&line)?;
+                let key = split_to_byte(&mut line, b':')?;
+                let key = HeaderName::from_str(key).map_err(HttpError::from)?;
+                if is_trailer_allowed(&key) {
+                    let builder = std::mem::replace(req, Builder::new());
+                    *req = builder.header(key, line.trim_start());
+                }
+            }
+        }
+    }
+}
+
+/// Framing, routing and hop-by-hop fields are dropped from the trailers, the
+/// head has already been acted upon by the time they arrive.
+fn is_trailer_allowed(name: &HeaderName) -> bool {
+    const FORBIDDEN: [HeaderName; 11] = [
+        CONTENT_LENGTH,
+        TRANSFER_ENCODING,
+        HOST,
+        CONNECTION,
+        TE,
+        TRAILER,
+        UPGRADE,
+        PROXY_AUTHENTICATE,
+        PROXY_AUTHORIZATION,
+        CONTENT_TYPE,
+        CONTENT_ENCODING,
+    ];
+
+    !FORBIDDEN.contains(name) && name.as_str() != "keep-alive"
+}
+
+fn is_chunked(headers: &HeaderMap) -> bool {
+    // chunked must be the final coding applied to a message body
+    headers
+        .get_all(TRANSFER_ENCODING)
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
 #[inline]
-fn request_from_slice(buf: &[u8]) -> Result<Builder, RequestError> {
+fn request_from_slice(buf: &[u8], limits: &Limits) -> Result<Builder, RequestError> {
     let mut buf = from_utf8(buf)?;
     let mut request_line = split_to_delimiter(&mut buf)?;
+    if request_line.len() > limits.max_request_line_len {
+        return Err(RequestError::UriTooLong);
+    }
 
     //request line = "METHOD PATH HTTP/VERSION\r\n"
     let method = split_to_byte(&mut request_line, b' ')?;
@@ -79,16 +348,22 @@ fn request_from_slice(buf: &[u8]) -> Result<Builder, RequestError> {
         .method(Method::try_from(method).map_err(HttpError::from)?)
         .uri(Uri::try_from(path).map_err(HttpError::from)?)
         .version(match version {
-            "HTTP/0.9" => Version::HTTP_09,
             "HTTP/1.0" => Version::HTTP_10,
             "HTTP/1.1" => Version::HTTP_11,
-            "HTTP/2.0" => Version::HTTP_2,
-            "HTTP/3.0" => Version::HTTP_3,
-            _ => return Err(RequestError::UnsupportedVersion),
+            version if version.starts_with("HTTP/") => {
+                return Err(RequestError::UnsupportedVersion)
+            }
+            _ => return Err(RequestError::InvalidFormat),
         });
 
     // header = "Name: Value\r\n"
+    let mut headers = 0;
     while let Ok(mut header) = split_to_delimiter(&mut buf) {
+        headers += 1;
+        if headers > limits.max_headers {
+            return Err(RequestError::HeadersTooLarge);
+        }
+
         let key = split_to_byte(&mut header, b':')?;
         builder = builder.header(key, header.trim_start());
     }
@@ -109,7 +384,7 @@ fn split_to_byte<'a>(buf: &mut &'a str, byte: u8) -> Result<&'a str, RequestErro
 
 #[inline]
 fn split_to_delimiter<'a>(buf: &mut &'a str) -> Result<&'a str, RequestError> {
-    if buf.len() == 0 {
+    if buf.is_empty() {
         return Err(RequestError::InvalidFormat);
     }
 
@@ -130,30 +405,350 @@ fn split_to_delimiter<'a>(buf: &mut &'a str) -> Result<&'a str, RequestError> {
 impl Encoder<Response> for ConnectionCodec {
     type Error = ResponseError;
 
+    /// Writes the response head and, for buffered bodies, the body itself.
+    /// Streamed bodies must be taken out of the response beforehand and sent
+    /// as [`Chunk`]s after it, chunked when the response says so and
+    /// delimited by closing the connection otherwise.
     fn encode(&mut self, response: Response, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
         write!(dst, "{:?} {:?}\r\n", response.version(), response.status())?;
 
+        // 1xx and 204 responses never carry a body nor announce its length
+        let status = response.status();
+        let bodiless = status.is_informational() || status == StatusCode::NO_CONTENT;
+
         for (key, value) in response.headers() {
+            if bodiless && key == CONTENT_LENGTH {
+                continue;
+            }
+
             let value = value.to_str()?;
             write!(dst, "{}: {}\r\n", key, value)?;
         }
 
-        if response.headers().get(CONTENT_LENGTH).is_none() {
-            let len = response
-                .body()
-                .as_ref()
-                .map(|b| b.len())
-                .unwrap_or_default();
-
-            write!(dst, "{}: {}\r\n", CONTENT_LENGTH, len)?;
+        // a 304 may only repeat the length the full response would have had
+        let chunked = is_chunked(response.headers());
+        let not_modified = status == StatusCode::NOT_MODIFIED;
+        if !bodiless
+            && !chunked
+            && !not_modified
+            && response.headers().get(CONTENT_LENGTH).is_none()
+        {
+            if let Some(len) = response.body().len() {
+                write!(dst, "{}: {}\r\n", CONTENT_LENGTH, len)?;
+            }
         }
+        self.chunked = chunked;
 
         write!(dst, "\r\n")?;
 
-        if let Some(body) = response.body() {
-            dst.extend_from_slice(&body);
+        if let Some(body) = response.body().as_bytes() {
+            dst.extend_from_slice(body);
+        }
+
+        Ok(())
+    }
+}
+
+impl Encoder<Chunk> for ConnectionCodec {
+    type Error = ResponseError;
+
+    fn encode(&mut self, chunk: Chunk, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        match chunk {
+            Chunk::Data(data) if data.is_empty() => {}
+            Chunk::Data(data) if !self.chunked => dst.extend_from_slice(&data),
+            Chunk::End if !self.chunked => {}
+            Chunk::Data(data) => {
+                write!(dst, "{:x}\r\n", data.len())?;
+                dst.extend_from_slice(&data);
+                dst.extend_from_slice(LINE_DELIMITER);
+            }
+            Chunk::End => dst.extend_from_slice(b"0\r\n\r\n"),
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_body_len: usize) -> Limits {
+        Limits {
+            max_body_len,
+            ..Default::default()
+        }
+    }
+
+    fn decode(codec: &mut ConnectionCodec, src: &mut BytesMut) -> Result<Request, RequestError> {
+        codec
+            .decode(src)
+            .map(|req| req.expect("a complete request"))
+    }
+
+    fn chunked(body: &str) -> BytesMut {
+        let head = "POST /pessoas HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        BytesMut::from(format!("{head}{body}").as_str())
+    }
+
+    #[test]
+    fn decodes_chunk_sizes_as_hex() {
+        let data = "a".repeat(0x1a);
+        let mut src = chunked(&format!("1A\r\n{data}\r\n3\r\nbcd\r\n0\r\n\r\n"));
+        let req = decode(&mut ConnectionCodec::default(), &mut src).unwrap();
+
+        assert_eq!(req.body().as_deref(), Some(format!("{data}bcd").as_bytes()));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn ignores_chunk_extensions() {
+        let mut src = chunked("4;name=value\r\nwiki\r\n5 ; ext\r\npedia\r\n0;last\r\n\r\n");
+        let req = decode(&mut ConnectionCodec::default(), &mut src).unwrap();
+
+        assert_eq!(req.body().as_deref(), Some(&b"wikipedia"[..]));
+    }
+
+    #[test]
+    fn decodes_chunks_split_across_reads() {
+        let mut codec = ConnectionCodec::default();
+        let mut src = chunked("4\r\nwi");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+
+        src.extend_from_slice(b"ki\r\n0\r\n");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+
+        src.extend_from_slice(b"\r\n");
+        let req = decode(&mut codec, &mut src).unwrap();
+        assert_eq!(req.body().as_deref(), Some(&b"wiki"[..]));
+    }
+
+    #[test]
+    fn rejects_malformed_chunk_size() {
+        let mut src = chunked("zz\r\nwiki\r\n0\r\n\r\n");
+        let err = decode(&mut ConnectionCodec::default(), &mut src).unwrap_err();
+
+        assert!(matches!(err, RequestError::InvalidChunkSize(_)));
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_missing_chunk_delimiter() {
+        let mut src = chunked("4\r\nwikiX\r\n0\r\n\r\n");
+        let err = decode(&mut ConnectionCodec::default(), &mut src).unwrap_err();
+
+        assert!(matches!(err, RequestError::InvalidFormat));
+    }
+
+    #[test]
+    fn rejects_chunked_body_over_the_limit() {
+        let mut codec = ConnectionCodec::new(limits(8));
+        // the chunks fit alone but not together
+        let mut src = chunked("5\r\nabcde\r\n5\r\nfghij\r\n0\r\n\r\n");
+        let err = decode(&mut codec, &mut src).unwrap_err();
+
+        assert!(matches!(err, RequestError::PayloadTooLarge));
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn leaves_pipelined_bytes_after_the_last_chunk() {
+        let mut codec = ConnectionCodec::default();
+        let mut src = chunked("4\r\nwiki\r\n0\r\n\r\nGET /contagem-pessoas HTTP/1.1\r\n\r\n");
+
+        let req = decode(&mut codec, &mut src).unwrap();
+        assert_eq!(req.body().as_deref(), Some(&b"wiki"[..]));
+        assert_eq!(src, &b"GET /contagem-pessoas HTTP/1.1\r\n\r\n"[..]);
+
+        let next = decode(&mut codec, &mut src).unwrap();
+        assert_eq!(next.uri(), "/contagem-pessoas");
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn keeps_trailers_but_not_framing_fields() {
+        let mut src = chunked(concat!(
+            "4\r\nwiki\r\n0\r\n",
+            "Checksum: abc\r\n",
+            "Content-Length: 4\r\n",
+            "Transfer-Encoding: gzip\r\n",
+            "Host: elsewhere\r\n",
+            "Connection: close\r\n",
+            "Keep-Alive: timeout=5\r\n",
+            "\r\n",
+        ));
+        let req = decode(&mut ConnectionCodec::default(), &mut src).unwrap();
+
+        let headers = req.headers();
+        assert_eq!(headers["checksum"], "abc");
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+        assert_eq!(headers.get_all(TRANSFER_ENCODING).iter().count(), 1);
+        assert!(!headers.contains_key(HOST));
+        assert!(!headers.contains_key(CONNECTION));
+        assert!(!headers.contains_key("keep-alive"));
+    }
+
+    fn rejects(src: &str, limits: Limits) -> RequestError {
+        let mut src = BytesMut::from(src);
+        ConnectionCodec::new(limits)
+            .decode(&mut src)
+            .expect_err("the request is rejected")
+    }
+
+    #[test]
+    fn maps_request_errors_to_statuses() {
+        let err = rejects("POST /pessoas HTTP/1.1\r\n\r\n", Limits::default());
+        assert!(matches!(err, RequestError::LengthRequired));
+        assert_eq!(err.status(), StatusCode::LENGTH_REQUIRED);
+
+        let err = rejects(
+            "POST /pessoas HTTP/1.1\r\nContent-Length: 9\r\n\r\n",
+            limits(8),
+        );
+        assert!(matches!(err, RequestError::PayloadTooLarge));
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let limits = Limits {
+            max_request_line_len: 32,
+            max_headers_len: 64,
+            max_headers: 2,
+            ..Default::default()
+        };
+        let path = "a".repeat(40);
+        let err = rejects(&format!("GET /{path} HTTP/1.1\r\n\r\n"), limits);
+        assert!(matches!(err, RequestError::UriTooLong));
+        assert_eq!(err.status(), StatusCode::URI_TOO_LONG);
+
+        let err = rejects("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n", limits);
+        assert!(matches!(err, RequestError::HeadersTooLarge));
+        assert_eq!(err.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+
+        let err = rejects("GET / HTTP/2.0\r\n\r\n", limits);
+        assert!(matches!(err, RequestError::UnsupportedVersion));
+        assert_eq!(err.status(), StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+        let response = crate::http::IntoResponse::into_response(err);
+        assert_eq!(response.status(), StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+
+        let err = rejects("GET / FTP/1.1\r\n\r\n", limits);
+        assert!(matches!(err, RequestError::InvalidFormat));
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+
+        let err = rejects("POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n", limits);
+        assert!(matches!(err, RequestError::InvalidContentLength(_)));
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_ambiguous_framing() {
+        let err = rejects(
+            "POST / HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n",
+            Limits::default(),
+        );
+        assert!(matches!(err, RequestError::InvalidFormat));
+
+        let err = rejects(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+            Limits::default(),
+        );
+        assert!(matches!(err, RequestError::InvalidFormat));
+    }
+
+    #[test]
+    fn decodes_pipelined_requests_from_one_buffer() {
+        let mut codec = ConnectionCodec::default();
+        let mut src = BytesMut::from(concat!(
+            "POST /pessoas HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}",
+            "GET /pessoas/1 HTTP/1.1\r\n\r\n",
+            "POST /pessoas HTTP/1.1\r\nContent-Length: 4\r\n\r\n[1]\n",
+            "GET /contagem",
+        ));
+
+        let first = decode(&mut codec, &mut src).unwrap();
+        assert_eq!(first.method(), Method::POST);
+        assert_eq!(first.body().as_deref(), Some(&b"{}"[..]));
+
+        let second = decode(&mut codec, &mut src).unwrap();
+        assert_eq!(second.uri(), "/pessoas/1");
+        assert_eq!(second.body(), &None);
+
+        let third = decode(&mut codec, &mut src).unwrap();
+        assert_eq!(third.body().as_deref(), Some(&b"[1]\n"[..]));
+
+        // the last one is still incomplete
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"-pessoas HTTP/1.1\r\n\r\n");
+        let fourth = decode(&mut codec, &mut src).unwrap();
+        assert_eq!(fourth.uri(), "/contagem-pessoas");
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_whole_body() {
+        let mut codec = ConnectionCodec::default();
+        let mut src = BytesMut::from("POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nab");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+
+        src.extend_from_slice(b"cdGET");
+        let req = decode(&mut codec, &mut src).unwrap();
+        assert_eq!(req.body().as_deref(), Some(&b"abcd"[..]));
+        assert_eq!(src, &b"GET"[..]);
+    }
+
+    #[test]
+    fn resumes_the_delimiter_search_across_reads() {
+        let mut codec = ConnectionCodec::default();
+        let mut src = BytesMut::from("GET / HTTP/1.1\r\nHost: x\r");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(codec.scanned, src.len());
+
+        // the delimiter straddles the two reads
+        src.extend_from_slice(b"\n\r");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"\n");
+        let req = decode(&mut codec, &mut src).unwrap();
+
+        assert_eq!(req.headers()[HOST], "x");
+        assert_eq!(codec.scanned, 0);
+    }
+
+    #[test]
+    fn enforces_head_limits_before_the_delimiter_arrives() {
+        let limits = Limits {
+            max_request_line_len: 16,
+            max_headers_len: 64,
+            ..Default::default()
+        };
+
+        // no line end within the request line limit
+        let mut codec = ConnectionCodec::new(limits);
+        let mut src = BytesMut::from("GET /aaaaaaaaaaaaaaaaaaaa");
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(RequestError::UriTooLong)
+        ));
+
+        let mut codec = ConnectionCodec::new(limits);
+        let mut src = BytesMut::from("GET / HTTP/1.1\r\n");
+        for _ in 0..4 {
+            assert!(codec.decode(&mut src).unwrap().is_none());
+            src.extend_from_slice(b"X-Filler: 0123\r\n");
+        }
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(RequestError::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn rejects_content_length_before_reading_the_body() {
+        let mut codec = ConnectionCodec::new(limits(1024));
+        let mut src = BytesMut::from("POST / HTTP/1.1\r\nContent-Length: 1025\r\n\r\n");
+
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(RequestError::PayloadTooLarge)
+        ));
+        assert!(src.capacity() < 1024);
+    }
+}
@@ -0,0 +1,19 @@
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+
+/// IMF-fixdate, the preferred format for dates in HTTP headers, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+const HTTP_DATE: &[FormatItem<'static>] = format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+);
+
+pub fn format(date: OffsetDateTime) -> String {
+    date.to_offset(time::UtcOffset::UTC)
+        .format(HTTP_DATE)
+        .expect("dates are always formattable")
+}
+
+pub fn parse(date: &str) -> Option<OffsetDateTime> {
+    time::PrimitiveDateTime::parse(date, HTTP_DATE)
+        .ok()
+        .map(|date| date.assume_utc())
+}
@@ -0,0 +1,59 @@
+use std::{future::Future, pin::Pin};
+
+use futures_util::{future::Map, FutureExt};
+
+use super::{
+    middleware::{Layered, Middleware},
+    IntoResponse, Request, Response,
+};
+
+pub type BoxFuture = Pin<Box<dyn Future<Output = Response> + Send>>;
+pub type BoxHandler<S> = Box<dyn Handler<S, Future = BoxFuture>>;
+
+/// Turns a request into a response, given a copy of the application state.
+pub trait Handler<S>: Send + Sync + 'static {
+    type Future: Future<Output = Response> + Send + 'static;
+
+    fn call(&self, request: Request, state: S) -> Self::Future;
+
+    fn boxed(self) -> BoxHandler<S>
+    where
+        Self: Sized,
+    {
+        Box::new(Boxed(self))
+    }
+
+    /// Wraps this handler with `middleware`, which runs before it. Layers
+    /// added later run first.
+    fn layer<M>(self, middleware: M) -> Layered<S, M>
+    where
+        Self: Sized,
+        S: 'static,
+        M: Middleware<S>,
+    {
+        Layered::new(self, middleware)
+    }
+}
+
+impl<S, F, Fut> Handler<S> for F
+where
+    F: Fn(Request, S) -> Fut + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: IntoResponse,
+{
+    type Future = Map<Fut, fn(Fut::Output) -> Response>;
+
+    fn call(&self, request: Request, state: S) -> Self::Future {
+        (self)(request, state).map(IntoResponse::into_response)
+    }
+}
+
+struct Boxed<H>(H);
+
+impl<S, H: Handler<S>> Handler<S> for Boxed<H> {
+    type Future = BoxFuture;
+
+    fn call(&self, request: Request, state: S) -> Self::Future {
+        Box::pin(self.0.call(request, state))
+    }
+}
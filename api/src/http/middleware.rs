@@ -0,0 +1,173 @@
+use std::{future::Future, sync::Arc, time::Duration, time::Instant};
+
+use http::{
+    header::{HeaderName, USER_AGENT},
+    HeaderValue,
+};
+use uuid::Uuid;
+
+use crate::error::ApiError;
+
+use super::{
+    handler::{BoxFuture, Handler},
+    IntoResponse, Request,
+};
+
+/// Code that runs around a handler, deciding whether and how to call the rest
+/// of the chain through [`Next`]. Closures taking `(Request, S, Next<S>)` are
+/// middlewares too.
+pub trait Middleware<S>: Send + Sync + 'static {
+    fn call(&self, request: Request, state: S, next: Next<S>) -> BoxFuture;
+}
+
+impl<S, F, Fut> Middleware<S> for F
+where
+    F: Fn(Request, S, Next<S>) -> Fut + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: IntoResponse,
+{
+    fn call(&self, request: Request, state: S, next: Next<S>) -> BoxFuture {
+        let future = (self)(request, state, next);
+        Box::pin(async move { future.await.into_response() })
+    }
+}
+
+/// The remainder of the chain after the current middleware.
+pub struct Next<S> {
+    inner: Arc<dyn Handler<S, Future = BoxFuture>>,
+}
+
+impl<S: 'static> Next<S> {
+    pub fn run(self, request: Request, state: S) -> BoxFuture {
+        self.inner.call(request, state)
+    }
+}
+
+/// A handler wrapped by a middleware, see [`Handler::layer`].
+pub struct Layered<S, M> {
+    inner: Arc<dyn Handler<S, Future = BoxFuture>>,
+    middleware: M,
+}
+
+impl<S: 'static, M> Layered<S, M> {
+    pub(super) fn new<H: Handler<S>>(inner: H, middleware: M) -> Self {
+        Self {
+            inner: Arc::from(inner.boxed()),
+            middleware,
+        }
+    }
+}
+
+impl<S: 'static, M: Middleware<S>> Handler<S> for Layered<S, M> {
+    type Future = BoxFuture;
+
+    fn call(&self, request: Request, state: S) -> Self::Future {
+        let next = Next {
+            inner: Arc::clone(&self.inner),
+        };
+        self.middleware.call(request, state, next)
+    }
+}
+
+/// Logs every request and how long it took to be handled.
+pub struct RequestLog;
+
+impl<S: Send + 'static> Middleware<S> for RequestLog {
+    fn call(&self, request: Request, state: S, next: Next<S>) -> BoxFuture {
+        let user = request.headers().get(USER_AGENT).unwrap_or_else(|| {
+            static UNKNOWN_AGENT: HeaderValue = HeaderValue::from_static("Unknown");
+            &UNKNOWN_AGENT
+        });
+        let id = request.extensions().get::<RequestId>().map(|id| id.0);
+
+        let path = request.uri().to_string();
+        tracing::info!(
+            target: "requests",
+            method = %request.method(),
+            %path,
+            ?user,
+            ?id,
+            r#""{} {path}" by {user:?}"#, request.method()
+        );
+
+        let future = next.run(request, state);
+        Box::pin(async move {
+            let now = Instant::now();
+            let resp = future.await;
+            tracing::debug!(
+                ?id,
+                ?resp,
+                "handled in {:?}, sending response",
+                now.elapsed()
+            );
+
+            resp
+        })
+    }
+}
+
+/// Identifier of the request being handled, set by [`SetRequestId`].
+#[derive(Clone, Copy, Debug)]
+pub struct RequestId(pub Uuid);
+
+static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Tags each request with an `x-request-id`, reusing the one sent by the
+/// client when present, and echoes it back on the response.
+pub struct SetRequestId;
+
+impl<S: Send + 'static> Middleware<S> for SetRequestId {
+    fn call(&self, mut request: Request, state: S, next: Next<S>) -> BoxFuture {
+        let id = request
+            .headers()
+            .get(&X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(Uuid::now_v7);
+        request.extensions_mut().insert(RequestId(id));
+
+        let future = next.run(request, state);
+        Box::pin(async move {
+            let mut resp = future.await;
+            let value = HeaderValue::from_str(&id.to_string()).expect("uuids are valid headers");
+            resp.headers_mut().insert(X_REQUEST_ID.clone(), value);
+
+            resp
+        })
+    }
+}
+
+/// Answers with `503 Service Unavailable` when the handler takes too long.
+pub struct Timeout(pub Duration);
+
+impl<S: Send + 'static> Middleware<S> for Timeout {
+    fn call(&self, request: Request, state: S, next: Next<S>) -> BoxFuture {
+        let duration = self.0;
+        let future = next.run(request, state);
+        Box::pin(async move {
+            match tokio::time::timeout(duration, future).await {
+                Ok(resp) => resp,
+                Err(_) => {
+                    tracing::warn!("request timed out after {duration:?}");
+                    ApiError::Timeout(duration).into_response()
+                }
+            }
+        })
+    }
+}
+
+/// Adds a fixed header to every response that does not set it already.
+pub struct SetHeader(pub HeaderName, pub HeaderValue);
+
+impl<S: Send + 'static> Middleware<S> for SetHeader {
+    fn call(&self, request: Request, state: S, next: Next<S>) -> BoxFuture {
+        let (name, value) = (self.0.clone(), self.1.clone());
+        let future = next.run(request, state);
+        Box::pin(async move {
+            let mut resp = future.await;
+            resp.headers_mut().entry(name).or_insert(value);
+
+            resp
+        })
+    }
+}
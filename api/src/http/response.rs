@@ -4,15 +4,23 @@ use http::{
     HeaderValue, StatusCode,
 };
 
-pub type Response = http::Response<Option<Bytes>>;
+use super::Body;
+
+pub type Response = http::Response<Body>;
 
 pub trait IntoResponse {
     fn into_response(self) -> Response;
 }
 
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
 impl IntoResponse for StatusCode {
     fn into_response(self) -> Response {
-        let mut response = http::Response::new(None);
+        let mut response = http::Response::new(Body::Empty);
         *response.status_mut() = self;
         response.headers_mut().insert(CONTENT_LENGTH, 0.into());
 
@@ -23,7 +31,7 @@ impl IntoResponse for StatusCode {
 impl IntoResponse for Bytes {
     fn into_response(self) -> Response {
         let body_len = self.len();
-        let mut response = http::Response::new(Some(self));
+        let mut response = http::Response::new(Body::Full(self));
 
         response.headers_mut().insert(
             CONTENT_TYPE,
@@ -47,7 +55,7 @@ where
 
         http::Response::builder()
             .header(http::header::CONTENT_TYPE, mime::JSON.as_ref())
-            .body(Some(json.into()))
+            .body(json.into())
             .expect("failed to create response")
     }
 }
@@ -60,7 +68,7 @@ impl IntoResponse for String {
 
 impl IntoResponse for &'static str {
     fn into_response(self) -> Response {
-        let mut response = http::Response::new(Some(Bytes::from(self)));
+        let mut response = http::Response::new(Body::Full(Bytes::from(self)));
 
         response.headers_mut().insert(
             CONTENT_TYPE,
@@ -71,6 +79,21 @@ impl IntoResponse for &'static str {
     }
 }
 
+impl IntoResponse for Body {
+    fn into_response(self) -> Response {
+        http::Response::new(self)
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
+}
+
 impl<B: IntoResponse> IntoResponse for (StatusCode, B) {
     fn into_response(self) -> Response {
         let mut response = self.1.into_response();
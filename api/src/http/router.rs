@@ -0,0 +1,382 @@
+use std::str::FromStr;
+
+use http::{
+    header::{ALLOW, CONTENT_LENGTH, LOCATION, TRANSFER_ENCODING},
+    HeaderValue, Method, StatusCode, Version,
+};
+
+use crate::error::ApiError;
+
+use super::{
+    handler::{BoxFuture, BoxHandler, Handler},
+    Body, IntoResponse, Request, CHUNKED,
+};
+
+/// What to do with requests whose path ends in a slash, e.g. `/pessoas/`.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum TrailingSlash {
+    /// Match as if the slash was not there.
+    #[default]
+    Ignore,
+    /// Only match routes declared with the trailing slash.
+    Strict,
+    /// Answer with a permanent redirect to the path without the slash.
+    Redirect,
+}
+
+impl FromStr for TrailingSlash {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Self::Ignore),
+            "strict" => Ok(Self::Strict),
+            "redirect" => Ok(Self::Redirect),
+            _ => Err("expected one of ignore, strict or redirect"),
+        }
+    }
+}
+
+/// Values captured by `:name` segments of the matched route, available as a
+/// request extension.
+#[derive(Clone, Debug, Default)]
+pub struct PathParams(Vec<(&'static str, String)>);
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum Segment {
+    Static(&'static str),
+    Param(&'static str),
+}
+
+struct Route<S> {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: BoxHandler<S>,
+}
+
+impl<S> Route<S> {
+    fn matches(&self, path: &[&str]) -> Option<PathParams> {
+        if self.segments.len() != path.len() {
+            return None;
+        }
+
+        let mut params = PathParams::default();
+        for (segment, part) in self.segments.iter().zip(path) {
+            match segment {
+                Segment::Static(s) if s == part => {}
+                Segment::Param(name) if !part.is_empty() => params.0.push((name, part.to_string())),
+                _ => return None,
+            }
+        }
+
+        Some(params)
+    }
+
+    fn params(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|s| matches!(s, Segment::Param(_)))
+            .count()
+    }
+}
+
+pub struct Router<S> {
+    routes: Vec<Route<S>>,
+    trailing_slash: TrailingSlash,
+}
+
+impl<S> Default for Router<S> {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            trailing_slash: TrailingSlash::default(),
+        }
+    }
+}
+
+impl<S: Send + 'static> Router<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `handler` for `method` on `path`, where segments starting
+    /// with `:` capture the value at that position, e.g. `/pessoas/:id`.
+    pub fn route<H: Handler<S>>(mut self, method: Method, path: &'static str, handler: H) -> Self {
+        let segments = split_path(path)
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => Segment::Param(name),
+                None => Segment::Static(segment),
+            })
+            .collect();
+
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: handler.boxed(),
+        });
+        self
+    }
+
+    pub fn trailing_slash(mut self, trailing_slash: TrailingSlash) -> Self {
+        self.trailing_slash = trailing_slash;
+        self
+    }
+
+    fn dispatch(&self, mut request: Request, state: S) -> BoxFuture {
+        let path = request.uri().path();
+        let trimmed = match path.strip_suffix('/') {
+            Some(trimmed) if !trimmed.is_empty() => trimmed,
+            _ => path,
+        };
+
+        let path = match self.trailing_slash {
+            TrailingSlash::Strict => path,
+            TrailingSlash::Ignore => trimmed,
+            TrailingSlash::Redirect if trimmed.len() == path.len() => path,
+            TrailingSlash::Redirect => {
+                let location = match request.uri().query() {
+                    Some(query) => format!("{trimmed}?{query}"),
+                    None => trimmed.to_string(),
+                };
+                let response = http::Response::builder()
+                    .status(StatusCode::PERMANENT_REDIRECT)
+                    .header(LOCATION, location)
+                    .body(Body::Empty)
+                    .expect("failed to create response");
+                return Box::pin(async move { response });
+            }
+        };
+        let parts: Vec<_> = split_path(path).collect();
+
+        // the most specific pattern wins, so `/pessoas/lote` takes precedence
+        // over `/pessoas/:id`
+        let matched = self
+            .routes
+            .iter()
+            .filter_map(|route| route.matches(&parts).map(|params| (route, params)))
+            .min_by_key(|(route, _)| route.params());
+        let Some((best, params)) = matched else {
+            let err =
+                ApiError::RouteNotFound(request.method().clone(), request.uri().path().into());
+            return Box::pin(async move { err.into_response() });
+        };
+
+        let candidates: Vec<_> = self
+            .routes
+            .iter()
+            .filter(|route| route.segments == best.segments)
+            .collect();
+        let find = |method: &Method| candidates.iter().find(|route| route.method == method);
+
+        let method = request.method().clone();
+        let (route, head) = match find(&method) {
+            Some(route) => (route, false),
+            None if method == Method::HEAD && find(&Method::GET).is_some() => {
+                (find(&Method::GET).unwrap(), true)
+            }
+            None => {
+                let allow = allow_header(candidates.iter().map(|route| &route.method));
+                let mut response = match method {
+                    Method::OPTIONS => StatusCode::NO_CONTENT.into_response(),
+                    method => {
+                        let path = request.uri().path().into();
+                        ApiError::MethodNotAllowed(method, path).into_response()
+                    }
+                };
+                response.headers_mut().insert(ALLOW, allow);
+                return Box::pin(async move { response });
+            }
+        };
+
+        request.extensions_mut().insert(params);
+
+        // HTTP/1.0 clients get streamed bodies delimited by the connection
+        let chunked = request.version() != Version::HTTP_10;
+        let future = route.handler.call(request, state);
+        if !head {
+            return future;
+        }
+
+        Box::pin(async move {
+            let mut response = future.await;
+            match response.body().len() {
+                Some(len) => {
+                    response
+                        .headers_mut()
+                        .entry(CONTENT_LENGTH)
+                        .or_insert_with(|| len.into());
+                }
+                // announced like the GET would be, rather than as empty
+                None if chunked => {
+                    response.headers_mut().insert(TRANSFER_ENCODING, CHUNKED);
+                }
+                None => {}
+            }
+
+            *response.body_mut() = Body::Empty;
+            response
+        })
+    }
+}
+
+impl<S: Send + 'static> Handler<S> for Router<S> {
+    type Future = BoxFuture;
+
+    fn call(&self, request: Request, state: S) -> Self::Future {
+        self.dispatch(request, state)
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+fn allow_header<'a>(methods: impl Iterator<Item = &'a Method>) -> HeaderValue {
+    let mut allow: Vec<_> = methods.map(Method::as_str).collect();
+    if allow.contains(&Method::GET.as_str()) {
+        allow.push(Method::HEAD.as_str());
+    }
+    allow.push(Method::OPTIONS.as_str());
+    allow.sort_unstable();
+    allow.dedup();
+
+    HeaderValue::from_str(&allow.join(", ")).expect("method names are valid header values")
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::CONTENT_TYPE;
+
+    use super::*;
+    use crate::http::Response;
+
+    async fn by_id(request: Request, _: ()) -> String {
+        let params = request.extensions().get::<PathParams>().unwrap();
+        format!("id {}", params.get("id").unwrap())
+    }
+
+    async fn batch(_: Request, _: ()) -> &'static str {
+        "batch"
+    }
+
+    async fn stream(_: Request, _: ()) -> Body {
+        Body::stream(futures_util::stream::empty())
+    }
+
+    fn router() -> Router<()> {
+        Router::new()
+            .route(Method::GET, "/pessoas/:id", by_id)
+            .route(Method::DELETE, "/pessoas/:id", by_id)
+            .route(Method::POST, "/pessoas/lote", batch)
+            .route(Method::GET, "/pessoas/lote", batch)
+            .route(Method::GET, "/stream", stream)
+    }
+
+    async fn call(router: &Router<()>, method: Method, uri: &str) -> Response {
+        let request = http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(None)
+            .unwrap();
+        router.dispatch(request, ()).await
+    }
+
+    fn body(response: &Response) -> &[u8] {
+        response.body().as_bytes().map_or(&[], |body| body)
+    }
+
+    fn problem_code(response: &Response) -> String {
+        let problem: serde_json::Value = serde_json::from_slice(body(response)).unwrap();
+        problem["code"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn prefers_static_segments_over_params() {
+        let router = router();
+
+        let response = call(&router, Method::GET, "/pessoas/lote").await;
+        assert_eq!(body(&response), b"batch");
+
+        let response = call(&router, Method::GET, "/pessoas/42").await;
+        assert_eq!(body(&response), b"id 42");
+    }
+
+    #[tokio::test]
+    async fn answers_unknown_paths_with_not_found() {
+        let response = call(&router(), Method::GET, "/pessoas/42/amigos").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(problem_code(&response), "route_not_found");
+    }
+
+    #[tokio::test]
+    async fn answers_unknown_methods_with_allowed_ones() {
+        let response = call(&router(), Method::PUT, "/pessoas/42").await;
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "DELETE, GET, HEAD, OPTIONS");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(problem_code(&response), "method_not_allowed");
+    }
+
+    #[tokio::test]
+    async fn answers_options_with_allowed_methods() {
+        let response = call(&router(), Method::OPTIONS, "/pessoas/lote").await;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[ALLOW], "GET, HEAD, OPTIONS, POST");
+    }
+
+    #[tokio::test]
+    async fn derives_head_from_get() {
+        let router = router();
+
+        let response = call(&router, Method::HEAD, "/pessoas/42").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+        assert!(response.headers().contains_key(CONTENT_TYPE));
+        assert!(matches!(response.body(), Body::Empty));
+
+        let response = call(&router, Method::HEAD, "/stream").await;
+        assert_eq!(response.headers()[TRANSFER_ENCODING], "chunked");
+        assert!(matches!(response.body(), Body::Empty));
+    }
+
+    #[tokio::test]
+    async fn ignores_trailing_slashes_by_default() {
+        let response = call(&router(), Method::GET, "/pessoas/42/").await;
+        assert_eq!(body(&response), b"id 42");
+    }
+
+    #[tokio::test]
+    async fn matches_trailing_slashes_strictly() {
+        let router = router().trailing_slash(TrailingSlash::Strict);
+
+        let response = call(&router, Method::GET, "/pessoas/42/").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = call(&router, Method::GET, "/pessoas/42").await;
+        assert_eq!(body(&response), b"id 42");
+    }
+
+    #[tokio::test]
+    async fn redirects_trailing_slashes_keeping_the_query() {
+        let router = router().trailing_slash(TrailingSlash::Redirect);
+
+        let response = call(&router, Method::GET, "/pessoas/42/?a=1").await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[LOCATION], "/pessoas/42?a=1");
+
+        let response = call(&router, Method::GET, "/pessoas/42").await;
+        assert_eq!(body(&response), b"id 42");
+    }
+}
@@ -0,0 +1,36 @@
+pub mod memory;
+pub mod redis;
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+pub type SharedStore = Arc<dyn KeyValueStore + Send + Sync>;
+
+/// Minimal key-value storage shared between api replicas. Entries given a
+/// `ttl` disappear once it elapses.
+#[async_trait::async_trait]
+pub trait KeyValueStore {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>>;
+    async fn set(&self, key: &str, value: Bytes, ttl: Option<Duration>) -> Result<()>;
+    /// Sets `key` only when it is not set yet, returning whether it was.
+    async fn set_nx(&self, key: &str, value: Bytes, ttl: Option<Duration>) -> Result<bool>;
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Picks the store from the `KV_STORE` variable, either `memory` or `redis`.
+/// Without it, nothing is shared.
+pub async fn from_env() -> Result<Option<SharedStore>> {
+    let store: SharedStore = match std::env::var("KV_STORE").as_deref() {
+        Err(_) => return Ok(None),
+        Ok("memory") => Arc::new(memory::MemoryStore::default()),
+        Ok("redis") => {
+            let addr = std::env::var("REDIS_ADDRESS").unwrap_or_else(|_| "0.0.0.0:6379".into());
+            Arc::new(redis::RedisStore::connect(addr).await?)
+        }
+        Ok(other) => anyhow::bail!("unknown KV_STORE {other:?}, expected memory or redis"),
+    };
+
+    Ok(Some(store))
+}
@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::KeyValueStore;
+
+/// Store living in the process memory, only shared by what runs inside it.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, (Bytes, Option<Instant>)>>,
+}
+
+impl MemoryStore {
+    fn live(entries: &mut HashMap<String, (Bytes, Option<Instant>)>, key: &str) -> Option<Bytes> {
+        match entries.get(key) {
+            Some((_, Some(expires_at))) if *expires_at <= Instant::now() => {
+                entries.remove(key);
+                None
+            }
+            Some((value, _)) => Some(value.clone()),
+            None => None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyValueStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        let mut entries = self.entries.lock().unwrap();
+        Ok(Self::live(&mut entries, key))
+    }
+
+    async fn set(&self, key: &str, value: Bytes, ttl: Option<Duration>) -> Result<()> {
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key.to_string(), (value, expires_at));
+
+        Ok(())
+    }
+
+    async fn set_nx(&self, key: &str, value: Bytes, ttl: Option<Duration>) -> Result<bool> {
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        let mut entries = self.entries.lock().unwrap();
+        if Self::live(&mut entries, key).is_some() {
+            return Ok(false);
+        }
+
+        entries.insert(key.to_string(), (value, expires_at));
+        Ok(true)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sets_gets_and_deletes() {
+        let store = MemoryStore::default();
+        assert_eq!(store.get("k").await.unwrap(), None);
+
+        store.set("k", Bytes::from("v1"), None).await.unwrap();
+        store.set("k", Bytes::from("v2"), None).await.unwrap();
+        assert_eq!(store.get("k").await.unwrap(), Some(Bytes::from("v2")));
+
+        store.delete("k").await.unwrap();
+        assert_eq!(store.get("k").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn sets_only_missing_keys_with_set_nx() {
+        let store = MemoryStore::default();
+        assert!(store.set_nx("k", Bytes::from("v1"), None).await.unwrap());
+        assert!(!store.set_nx("k", Bytes::from("v2"), None).await.unwrap());
+        assert_eq!(store.get("k").await.unwrap(), Some(Bytes::from("v1")));
+    }
+
+    #[tokio::test]
+    async fn expires_entries() {
+        let store = MemoryStore::default();
+        let ttl = Some(Duration::from_millis(20));
+        store.set("k", Bytes::from("v1"), ttl).await.unwrap();
+        assert!(store.get("k").await.unwrap().is_some());
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(store.get("k").await.unwrap(), None);
+        assert!(store.set_nx("k", Bytes::from("v2"), ttl).await.unwrap());
+    }
+}
@@ -0,0 +1,336 @@
+use std::{io, str::from_utf8, sync::Mutex, time::Duration};
+
+use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use memchr::memmem;
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::config;
+
+use super::KeyValueStore;
+
+type Connection = Framed<TcpStream, RespCodec>;
+
+/// Store backed by a Redis server, spoken to over RESP with a small pool of
+/// connections that are opened on demand.
+pub struct RedisStore {
+    addr: String,
+    idle: Mutex<Vec<Connection>>,
+    /// Connections kept open between commands.
+    max_idle: usize,
+    timeout: Duration,
+}
+
+impl RedisStore {
+    /// Connects to `addr`, given as `host:port` with an optional `redis://`
+    /// prefix, and checks that the server answers.
+    pub async fn connect(addr: impl Into<String>) -> Result<Self> {
+        let addr = addr.into();
+        let store = Self {
+            addr: addr.strip_prefix("redis://").unwrap_or(&addr).to_string(),
+            idle: Mutex::default(),
+            max_idle: config::env_or("REDIS_POOL_SIZE", 16),
+            timeout: config::env_duration_ms("REDIS_TIMEOUT_MS", Duration::from_secs(1)),
+        };
+        store.command(&[b"PING"]).await?;
+
+        Ok(store)
+    }
+
+    async fn command(&self, args: &[&[u8]]) -> Result<Reply> {
+        let conn = self.idle.lock().unwrap().pop();
+        let mut conn = match conn {
+            Some(conn) => conn,
+            None => {
+                let stream = tokio::time::timeout(self.timeout, TcpStream::connect(&self.addr))
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+                Framed::new(stream, RespCodec)
+            }
+        };
+
+        let reply = tokio::time::timeout(self.timeout, async {
+            conn.send(args).await?;
+            conn.next()
+                .await
+                .unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))
+        })
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        // connections are only reused after a complete exchange
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(conn);
+        }
+        drop(idle);
+
+        match reply {
+            Reply::Error(err) => anyhow::bail!("redis replied with {err}"),
+            reply => Ok(reply),
+        }
+    }
+
+    async fn set_with(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl: Option<Duration>,
+        nx: bool,
+    ) -> Result<Reply> {
+        let ttl = ttl.map(|ttl| ttl.as_millis().max(1).to_string());
+        let mut args: Vec<&[u8]> = vec![b"SET", key.as_bytes(), value];
+        if let Some(ttl) = &ttl {
+            args.extend([b"PX".as_slice(), ttl.as_bytes()]);
+        }
+        if nx {
+            args.push(b"NX");
+        }
+
+        self.command(&args).await
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyValueStore for RedisStore {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        match self.command(&[b"GET", key.as_bytes()]).await? {
+            Reply::Bulk(value) => Ok(value),
+            reply => anyhow::bail!("unexpected reply to GET: {reply:?}"),
+        }
+    }
+
+    async fn set(&self, key: &str, value: Bytes, ttl: Option<Duration>) -> Result<()> {
+        self.set_with(key, &value, ttl, false).await?;
+        Ok(())
+    }
+
+    async fn set_nx(&self, key: &str, value: Bytes, ttl: Option<Duration>) -> Result<bool> {
+        // a nil reply means the key was already set
+        let reply = self.set_with(key, &value, ttl, true).await?;
+        Ok(!matches!(reply, Reply::Bulk(None)))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.command(&[b"DEL", key.as_bytes()]).await?;
+        Ok(())
+    }
+}
+
+/// Replies to the commands sent by [`RedisStore`], which only looks into
+/// errors and bulk strings.
+#[derive(Debug)]
+enum Reply {
+    Simple,
+    Error(String),
+    Integer,
+    Bulk(Option<Bytes>),
+    Array,
+}
+
+/// Encodes commands as arrays of bulk strings and decodes replies.
+struct RespCodec;
+
+impl Encoder<&[&[u8]]> for RespCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, args: &[&[u8]], dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+        for arg in args {
+            dst.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            dst.extend_from_slice(arg);
+            dst.extend_from_slice(b"\r\n");
+        }
+
+        Ok(())
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Reply;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some((reply, len)) = parse_reply(src)? else {
+            return Ok(None);
+        };
+
+        src.advance(len);
+        Ok(Some(reply))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parses the reply at the start of `src`, returning it with its length, or
+/// `None` when more bytes are needed.
+fn parse_reply(src: &[u8]) -> io::Result<Option<(Reply, usize)>> {
+    let Some(line_end) = memmem::find(src, b"\r\n") else {
+        return Ok(None);
+    };
+    let Some((&kind, line)) = src[..line_end].split_first() else {
+        return Err(invalid("empty reply"));
+    };
+    let line = from_utf8(line).map_err(|_| invalid("reply is not utf-8"))?;
+    let mut len = line_end + 2;
+
+    let reply = match kind {
+        b'+' => Reply::Simple,
+        b'-' => Reply::Error(line.to_string()),
+        b':' => parse_int(line).map(|_| Reply::Integer)?,
+        b'$' => match parse_int(line)? {
+            -1 => Reply::Bulk(None),
+            size => {
+                let size = usize::try_from(size).map_err(|_| invalid("negative bulk size"))?;
+                if src.len() < len + size + 2 {
+                    return Ok(None);
+                }
+
+                let value = Bytes::copy_from_slice(&src[len..len + size]);
+                len += size + 2;
+                Reply::Bulk(Some(value))
+            }
+        },
+        b'*' => {
+            // skips over the items, `-1` meaning a nil array
+            for _ in 0..parse_int(line)?.max(0) {
+                let Some((_, item_len)) = parse_reply(&src[len..])? else {
+                    return Ok(None);
+                };
+                len += item_len;
+            }
+            Reply::Array
+        }
+        _ => return Err(invalid("unknown reply type")),
+    };
+
+    Ok(Some((reply, len)))
+}
+
+fn parse_int(line: &str) -> io::Result<i64> {
+    line.parse()
+        .map_err(|_| invalid("invalid integer in reply"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn parse(src: &[u8]) -> Option<(Reply, usize)> {
+        parse_reply(src).unwrap()
+    }
+
+    #[test]
+    fn encodes_commands_as_arrays_of_bulk_strings() {
+        let mut dst = BytesMut::new();
+        RespCodec.encode(&[b"GET", b"key"], &mut dst).unwrap();
+        assert_eq!(&dst[..], b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert!(matches!(parse(b"+OK\r\n"), Some((Reply::Simple, 5))));
+        assert!(matches!(parse(b":12\r\n"), Some((Reply::Integer, 5))));
+        assert!(matches!(parse(b"$-1\r\n"), Some((Reply::Bulk(None), 5))));
+        assert!(matches!(
+            parse(b"*2\r\n:1\r\n$1\r\na\r\n"),
+            Some((Reply::Array, 15))
+        ));
+        assert!(matches!(parse(b"-ERR no\r\n"), Some((Reply::Error(err), 9)) if err == "ERR no"));
+
+        let Some((Reply::Bulk(Some(value)), 11)) = parse(b"$5\r\nhello\r\n") else {
+            panic!("expected a bulk string");
+        };
+        assert_eq!(&value[..], b"hello");
+    }
+
+    #[test]
+    fn waits_for_complete_replies() {
+        assert!(parse(b"$5\r\nhel").is_none());
+        assert!(parse(b"*2\r\n:1\r\n").is_none());
+        assert!(parse(b"+OK").is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_replies() {
+        assert!(parse_reply(b"?\r\n").is_err());
+        assert!(parse_reply(b":x\r\n").is_err());
+        assert!(parse_reply(b"\r\n").is_err());
+    }
+
+    /// Answers every command read from the first connection with the next
+    /// reply, returning the commands it got.
+    async fn serve(
+        replies: &'static [&'static [u8]],
+    ) -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            for reply in replies {
+                let mut buf = [0; 1024];
+                let read = socket.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..read]);
+                socket.write_all(reply).await.unwrap();
+            }
+            received
+        });
+
+        (addr, server)
+    }
+
+    #[tokio::test]
+    async fn speaks_resp_over_a_reused_connection() {
+        let (addr, server) = serve(&[b"+PONG\r\n", b"+OK\r\n", b"$-1\r\n", b"$2\r\nv1\r\n"]).await;
+        let store = RedisStore::connect(format!("redis://{addr}"))
+            .await
+            .unwrap();
+
+        store
+            .set("k", Bytes::from("v1"), Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        assert!(!store.set_nx("k", Bytes::from("v2"), None).await.unwrap());
+        assert_eq!(store.get("k").await.unwrap(), Some(Bytes::from("v1")));
+
+        let received = server.await.unwrap();
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.contains("$3\r\nSET\r\n$1\r\nk\r\n$2\r\nv1\r\n$2\r\nPX\r\n$4\r\n1000\r\n"));
+        assert!(received.contains("$2\r\nNX\r\n"));
+    }
+
+    #[tokio::test]
+    async fn surfaces_error_replies() {
+        let (addr, _server) = serve(&[b"+PONG\r\n", b"-ERR wrong\r\n"]).await;
+        let store = RedisStore::connect(addr).await.unwrap();
+
+        let err = store.delete("k").await.unwrap_err();
+        assert!(err.to_string().contains("ERR wrong"));
+    }
+
+    #[tokio::test]
+    async fn times_out_as_io_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let store = RedisStore {
+            addr: listener.local_addr().unwrap().to_string(),
+            idle: Mutex::default(),
+            max_idle: 1,
+            timeout: Duration::from_millis(50),
+        };
+
+        // the server accepts but never replies
+        let err = store.get("k").await.unwrap_err();
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -1,14 +1,300 @@
+pub mod batching;
+pub mod cached;
+pub mod kv;
+pub mod memory;
+pub mod nicknames;
 pub mod sql;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use std::{fmt, future::Future, pin::Pin, str::FromStr, sync::Arc};
 
 use anyhow::Result;
+use futures_util::Stream;
+use time::{format_description::well_known::Iso8601, Date};
+use tokio::sync::mpsc;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 use uuid::Uuid;
 
 use crate::domains::Person;
 
+pub type SharedRepository = Arc<dyn PeopleRepository + Send + Sync>;
+
+/// Batches of people, as read by [`PeopleRepository::stream_all`].
+pub type PeopleStream = Pin<Box<dyn Stream<Item = Result<Vec<Person>>> + Send>>;
+
+/// How many people are read at a time when streaming them.
+pub const STREAM_BATCH_SIZE: usize = 500;
+
+/// Connects to the database at `url`, picking the repository by its scheme,
+/// and brings its schema up to date when `migrate` is set.
+pub async fn connect(url: &str, migrate: bool) -> Result<SharedRepository> {
+    let scheme = url.split_once(':').map_or(url, |(scheme, _)| scheme);
+    let repository: SharedRepository = match scheme {
+        "postgres" | "postgresql" => {
+            let repository = sql::SqlPeopleRepository::connect(url).await?;
+            if migrate {
+                repository.migrate().await?;
+            }
+            Arc::new(repository)
+        }
+        #[cfg(feature = "sqlite")]
+        "sqlite" => {
+            let repository = sqlite::SqlitePeopleRepository::connect(url).await?;
+            if migrate {
+                repository.migrate().await?;
+            }
+            Arc::new(repository)
+        }
+        _ => anyhow::bail!("unsupported database url scheme {scheme:?}"),
+    };
+
+    Ok(repository)
+}
+
+/// What to look for with [`PeopleRepository::search_many`].
+#[derive(Clone, Debug)]
+pub struct SearchQuery {
+    pub term: String,
+    pub mode: SearchMode,
+    /// Orders people by relevance, and in trigram mode also finds those
+    /// whose fields are only similar to the term where the repository
+    /// supports it.
+    pub ranked: bool,
+    /// Lowest similarity, from 0 to 1, of people found without containing
+    /// the term, in trigram mode.
+    pub min_score: f32,
+    /// Lowercased stack items people must all have.
+    pub stack: Vec<String>,
+    pub born_from: Option<Date>,
+    pub born_until: Option<Date>,
+    /// Ignored by ranked searches, which go by relevance.
+    pub sort: SearchSort,
+    /// Only people after this one in `sort` order, never set on ranked
+    /// searches.
+    pub after: Option<Cursor>,
+    pub limit: usize,
+}
+
+/// How the term of a search is matched against people.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SearchMode {
+    /// Name, nickname and stack contain the term as it's written.
+    #[default]
+    Trigram,
+    /// Every word of the term is found among the words of the person,
+    /// regardless of accents and, where the repository supports it, of
+    /// inflection.
+    FullText,
+}
+
+/// Order of the people found by a search that isn't ranked. Ties are broken
+/// by id, so by creation time since ids are UUIDv7.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SearchSort {
+    #[default]
+    Oldest,
+    Newest,
+    Youngest,
+    Eldest,
+}
+
+impl SearchSort {
+    pub fn by_birthday(self) -> bool {
+        matches!(self, Self::Youngest | Self::Eldest)
+    }
+
+    pub fn descending(self) -> bool {
+        matches!(self, Self::Newest | Self::Youngest)
+    }
+
+    /// `ORDER BY` clause of the sort in SQL.
+    pub fn order_by(self) -> &'static str {
+        match self {
+            Self::Oldest => "id",
+            Self::Newest => "id DESC",
+            Self::Youngest => "birthday DESC, id DESC",
+            Self::Eldest => "birthday, id",
+        }
+    }
+}
+
+/// Where a page of search results resumes: right after the person it was
+/// taken from, written as its id, preceded by its birthday and a `.` when
+/// the search is sorted by birthday.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub birthday: Option<Date>,
+    pub id: Uuid,
+}
+
+impl Cursor {
+    pub fn new(person: &Person, sort: SearchSort) -> Self {
+        Self {
+            birthday: sort.by_birthday().then_some(*person.birthday),
+            id: person.id,
+        }
+    }
+
+    /// Whether `person` comes after the cursor in `sort` order.
+    pub fn precedes(&self, person: &Person, sort: SearchSort) -> bool {
+        let key = (sort.by_birthday().then_some(*person.birthday), person.id);
+        let cursor = (self.birthday, self.id);
+        if sort.descending() {
+            key < cursor
+        } else {
+            key > cursor
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(birthday) = self.birthday {
+            write!(f, "{birthday}.")?;
+        }
+        write!(f, "{}", self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (birthday, id) = match s.split_once('.') {
+            Some((birthday, id)) => (Some(Date::parse(birthday, &Iso8601::DATE)?), id),
+            None => (None, s),
+        };
+
+        Ok(Self {
+            birthday,
+            id: id.parse()?,
+        })
+    }
+}
+
+/// A person found by a search, with its relevance when ranked.
+#[derive(Clone, Debug, serde::Serialize, sqlx::FromRow)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub person: Person,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+}
+
+/// How much matching the nickname, name and stack weighs in a search score.
+pub const SCORE_WEIGHTS: [f32; 3] = [3.0, 2.0, 1.0];
+
+/// Escapes `%`, `_` and `\` so `term` only matches itself in a `LIKE`.
+pub fn escape_like(term: &str) -> String {
+    let mut escaped = String::with_capacity(term.len());
+    for c in term.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Lowercased words of `text` with their accents removed, as matched by
+/// full-text searches that don't stem.
+pub fn fold_words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            word.nfd()
+                .filter(|c| !is_combining_mark(*c))
+                .flat_map(char::to_lowercase)
+                .collect()
+        })
+}
+
+/// The nickname is already taken, raised by repositories that check it
+/// before the database does.
+#[derive(Debug, thiserror::Error)]
+#[error("nickname already taken")]
+pub struct DuplicateNickname;
+
+/// Outcome of a write guarded by an expected version.
+#[derive(Debug)]
+pub enum Written {
+    /// The write was applied, leaving the person at `version`.
+    Applied {
+        version: i32,
+    },
+    NotFound,
+    /// The person exists but its version is not the expected one.
+    VersionMismatch,
+}
+
+/// Streams the batches sent by the task `produce` returns, which ends early
+/// once its sends fail because the stream was dropped. Only a couple of
+/// batches are read ahead of the consumer.
+fn spawn_stream<F>(produce: impl FnOnce(mpsc::Sender<Result<Vec<Person>>>) -> F) -> PeopleStream
+where
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(2);
+    let task = produce(sender.clone());
+    tokio::spawn(async move {
+        if let Err(err) = task.await {
+            let _ = sender.send(Err(err)).await;
+        }
+    });
+
+    Box::pin(futures_util::stream::unfold(
+        receiver,
+        |mut receiver| async move { receiver.recv().await.map(|batch| (batch, receiver)) },
+    ))
+}
+
 #[async_trait::async_trait]
 pub trait PeopleRepository {
     async fn find_one(&self, id: Uuid) -> Result<Option<Person>>;
-    async fn search_many(&self, term: &str) -> Result<Vec<Person>>;
+    async fn search_many(&self, query: &SearchQuery) -> Result<Vec<SearchHit>>;
     async fn insert_many(&self, people: &[Person]) -> Result<()>;
+    /// Like [`insert_many`](Self::insert_many), but skips people whose
+    /// nickname is already taken instead of failing, returning the ids of
+    /// those actually inserted.
+    async fn try_insert_many(&self, people: &[Person]) -> Result<Vec<Uuid>>;
     async fn count_people(&self) -> Result<i64>;
+    /// Every stored nickname along with the id of its owner.
+    async fn list_nicknames(&self) -> Result<Vec<(String, Uuid)>>;
+
+    /// Replaces every field of the person with the same id, as long as its
+    /// current version is `expected_version` when one is given.
+    async fn update_one(&self, person: &Person, expected_version: Option<i32>) -> Result<Written>;
+    async fn delete_one(&self, id: Uuid, expected_version: Option<i32>) -> Result<Written>;
+    /// Every person, oldest first, read a batch at a time so they don't all
+    /// sit in memory at once.
+    async fn stream_all(&self) -> Result<PeopleStream>;
+
+    /// Persists writes that were acknowledged but not stored yet.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_words() {
+        let words: Vec<_> = fold_words("João-Pedro  AÇÃO, c++ 2024").collect();
+        assert_eq!(words, ["joao", "pedro", "acao", "c", "2024"]);
+    }
+
+    #[test]
+    fn folds_nothing_out_of_punctuation() {
+        assert_eq!(fold_words(" -, !").next(), None);
+    }
+
+    #[test]
+    fn escapes_like_wildcards() {
+        assert_eq!(escape_like(r"100%_a\b"), r"100\%\_a\\b");
+    }
 }
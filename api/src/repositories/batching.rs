@@ -0,0 +1,475 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use crossbeam_queue::SegQueue;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::{config, domains::Person};
+
+use super::{
+    nicknames::NicknameIndexRepository, PeopleRepository, PeopleStream, SearchHit, SearchQuery,
+    Written,
+};
+
+#[derive(Clone, Debug)]
+pub struct BatchConfig {
+    /// People written by a single `INSERT`. Reaching it triggers a flush.
+    pub batch_size: usize,
+    /// How long a person may stay queued before being flushed.
+    pub max_delay: Duration,
+    /// Attempts of a failing flush before leaving the queue for the next one.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled after every attempt.
+    pub retry_backoff: Duration,
+}
+
+impl BatchConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            batch_size: config::env_or("BATCH_SIZE", default.batch_size).max(1),
+            max_delay: config::env_duration_ms("BATCH_MAX_DELAY_MS", default.max_delay),
+            max_retries: config::env_or("BATCH_MAX_RETRIES", default.max_retries),
+            retry_backoff: config::env_duration_ms("BATCH_RETRY_BACKOFF_MS", default.retry_backoff),
+        }
+    }
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 500,
+            max_delay: Duration::from_millis(50),
+            max_retries: 5,
+            retry_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Metrics {
+    /// Batches written since startup.
+    batches: AtomicUsize,
+    /// People written since startup.
+    flushed: AtomicUsize,
+    /// People skipped at flush time because their nickname was stored by
+    /// another replica.
+    dropped: AtomicUsize,
+    /// Failed attempts to write a batch.
+    failures: AtomicUsize,
+    /// Time spent by the last batch write, in microseconds.
+    last_flush_us: AtomicU64,
+}
+
+struct Shared {
+    inner: Arc<NicknameIndexRepository>,
+    config: BatchConfig,
+    queue: SegQueue<Person>,
+    /// Queued people by id, so they can be read before being written. People
+    /// written ahead of their batch are no longer here, and skipped once
+    /// popped from `queue`.
+    queued: RwLock<HashMap<Uuid, Person>>,
+    /// Woken when the queue reaches a full batch.
+    full: Notify,
+    /// Held while writing, so batches are not written concurrently.
+    flushing: tokio::sync::Mutex<()>,
+    metrics: Metrics,
+}
+
+/// Write-behind layer over a nickname index: inserted people are queued and
+/// acknowledged right away, and a background task writes them in batches.
+///
+/// Nicknames are reserved in the index before acknowledging, so taken ones
+/// are rejected up front just like without batching, and queued people can
+/// be read by id but are only found by searches once written. Updating or
+/// deleting a queued person writes it first, on its own. A nickname
+/// stored by another replica in the meantime is only noticed at flush time,
+/// dropping the person with a warning.
+pub struct BatchingPeopleRepository {
+    shared: Arc<Shared>,
+}
+
+impl BatchingPeopleRepository {
+    pub fn new(inner: Arc<NicknameIndexRepository>, config: BatchConfig) -> Self {
+        let shared = Arc::new(Shared {
+            inner,
+            config,
+            queue: SegQueue::new(),
+            queued: RwLock::default(),
+            full: Notify::new(),
+            flushing: tokio::sync::Mutex::default(),
+            metrics: Metrics::default(),
+        });
+
+        tokio::spawn(Arc::clone(&shared).run());
+
+        Self { shared }
+    }
+
+    /// Reserves the nicknames of `people` and queues them. With `all`, nothing
+    /// is queued unless every nickname is free.
+    fn enqueue(&self, people: &[Person], all: bool) -> Result<Vec<Uuid>> {
+        let reserved = self.shared.inner.reserve(people, all)?;
+
+        let mut queued = self.shared.queued.write().unwrap();
+        let ids = reserved
+            .into_iter()
+            .map(|person| {
+                queued.insert(person.id, person.clone());
+                self.shared.queue.push(person.clone());
+                person.id
+            })
+            .collect();
+        drop(queued);
+
+        if self.shared.queued.read().unwrap().len() >= self.shared.config.batch_size {
+            self.shared.full.notify_one();
+        }
+
+        Ok(ids)
+    }
+}
+
+impl Shared {
+    async fn run(self: Arc<Self>) {
+        loop {
+            let _ = tokio::time::timeout(self.config.max_delay, self.full.notified()).await;
+
+            if let Err(err) = self.flush_queued().await {
+                tracing::error!(
+                    %err,
+                    queued = self.queued.read().unwrap().len(),
+                    "failed to flush queued people, will retry"
+                );
+            }
+        }
+    }
+
+    /// Writes batches until the queue is empty, retrying with backoff when
+    /// it fails. Other flushes may go in between attempts.
+    async fn flush_queued(&self) -> Result<()> {
+        let mut backoff = self.config.retry_backoff;
+        let mut attempt = 0;
+
+        loop {
+            match self.write_queued().await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < self.config.max_retries => {
+                    tracing::warn!(%err, attempt, "failed to write batch, retrying in {backoff:?}");
+
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Writes batches until the queue is empty, putting a failed batch back
+    /// in the queue.
+    async fn write_queued(&self) -> Result<()> {
+        let _flushing = self.flushing.lock().await;
+
+        while !self.queue.is_empty() {
+            let batch: Vec<_> = {
+                let queued = self.queued.read().unwrap();
+                std::iter::from_fn(|| self.queue.pop())
+                    .filter(|person| queued.contains_key(&person.id))
+                    .take(self.config.batch_size)
+                    .collect()
+            };
+            if batch.is_empty() {
+                continue;
+            }
+
+            if let Err(err) = self.write(&batch).await {
+                self.metrics.failures.fetch_add(1, Ordering::Relaxed);
+                for person in batch {
+                    self.queue.push(person);
+                }
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the person `id` if it is still queued, ahead of its batch and
+    /// without retrying, so a write to it does not wait for the whole queue.
+    async fn write_one(&self, id: Uuid) -> Result<()> {
+        if !self.queued.read().unwrap().contains_key(&id) {
+            return Ok(());
+        }
+
+        let _flushing = self.flushing.lock().await;
+        let Some(person) = self.queued.read().unwrap().get(&id).cloned() else {
+            return Ok(());
+        };
+
+        let written = self.write(std::slice::from_ref(&person)).await;
+        if written.is_err() {
+            self.metrics.failures.fetch_add(1, Ordering::Relaxed);
+        }
+
+        written
+    }
+
+    async fn write(&self, batch: &[Person]) -> Result<()> {
+        let now = Instant::now();
+        let inserted = self.inner.insert_reserved(batch).await?;
+        self.metrics
+            .last_flush_us
+            .store(now.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+        let dropped = batch.len() - inserted.len();
+        if dropped > 0 {
+            let inserted: HashSet<_> = inserted.iter().collect();
+            for person in batch.iter().filter(|person| !inserted.contains(&person.id)) {
+                tracing::warn!(id = %person.id, nickname = person.nickname.as_str(), "nickname already stored, dropping queued person");
+            }
+        }
+
+        let mut queued = self.queued.write().unwrap();
+        for person in batch {
+            queued.remove(&person.id);
+        }
+        drop(queued);
+
+        let batches = self.metrics.batches.fetch_add(1, Ordering::Relaxed) + 1;
+        let flushed = self
+            .metrics
+            .flushed
+            .fetch_add(inserted.len(), Ordering::Relaxed)
+            + inserted.len();
+        let dropped = self.metrics.dropped.fetch_add(dropped, Ordering::Relaxed) + dropped;
+        tracing::debug!(
+            target: "batching",
+            batch = batch.len(),
+            depth = self.queued.read().unwrap().len(),
+            latency_us = self.metrics.last_flush_us.load(Ordering::Relaxed),
+            batches,
+            flushed,
+            dropped,
+            failures = self.metrics.failures.load(Ordering::Relaxed),
+            "flushed batch"
+        );
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl PeopleRepository for BatchingPeopleRepository {
+    async fn find_one(&self, id: Uuid) -> Result<Option<Person>> {
+        let queued = self.shared.queued.read().unwrap().get(&id).cloned();
+        if queued.is_some() {
+            return Ok(queued);
+        }
+
+        self.shared.inner.find_one(id).await
+    }
+
+    async fn search_many(&self, query: &SearchQuery) -> Result<Vec<SearchHit>> {
+        self.shared.inner.search_many(query).await
+    }
+
+    async fn insert_many(&self, people: &[Person]) -> Result<()> {
+        self.enqueue(people, true).map(drop)
+    }
+
+    async fn try_insert_many(&self, people: &[Person]) -> Result<Vec<Uuid>> {
+        self.enqueue(people, false)
+    }
+
+    async fn count_people(&self) -> Result<i64> {
+        let stored = self.shared.inner.count_people().await?;
+        let queued = self.shared.queued.read().unwrap().len();
+        Ok(stored + queued as i64)
+    }
+
+    async fn list_nicknames(&self) -> Result<Vec<(String, Uuid)>> {
+        self.shared.inner.list_nicknames().await
+    }
+
+    async fn update_one(&self, person: &Person, expected_version: Option<i32>) -> Result<Written> {
+        // the person may still be queued
+        self.shared.write_one(person.id).await?;
+        self.shared.inner.update_one(person, expected_version).await
+    }
+
+    async fn delete_one(&self, id: Uuid, expected_version: Option<i32>) -> Result<Written> {
+        self.shared.write_one(id).await?;
+        self.shared.inner.delete_one(id, expected_version).await
+    }
+
+    async fn stream_all(&self) -> Result<PeopleStream> {
+        self.shared.flush_queued().await?;
+        self.shared.inner.stream_all().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.shared.flush_queued().await?;
+        self.shared.inner.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use time::OffsetDateTime;
+
+    use crate::{
+        domains::{Birthday, Name, Nickname},
+        repositories::{
+            memory::InMemoryPeopleRepository, DuplicateNickname, SearchMode, SharedRepository,
+        },
+    };
+
+    use super::*;
+
+    fn person(nickname: &str) -> Person {
+        Person {
+            id: Uuid::now_v7(),
+            name: Name::new("Ana").unwrap(),
+            nickname: Nickname::new(nickname).unwrap(),
+            birthday: Birthday::parse("2000-01-01").unwrap(),
+            stack: None,
+            updated_at: OffsetDateTime::now_utc(),
+            version: 1,
+        }
+    }
+
+    /// A batching repository that only writes when told to, along with the
+    /// database it writes to.
+    async fn repository(stored: &[Person]) -> (BatchingPeopleRepository, SharedRepository) {
+        let database: SharedRepository = Arc::new(InMemoryPeopleRepository::default());
+        database.insert_many(stored).await.unwrap();
+
+        let index = NicknameIndexRepository::warm(database.clone())
+            .await
+            .unwrap();
+        let config = BatchConfig {
+            batch_size: 1000,
+            max_delay: Duration::from_secs(3600),
+            ..Default::default()
+        };
+
+        (
+            BatchingPeopleRepository::new(Arc::new(index), config),
+            database,
+        )
+    }
+
+    async fn search(repository: &dyn PeopleRepository, term: &str) -> usize {
+        let query = SearchQuery {
+            term: term.to_string(),
+            mode: SearchMode::Trigram,
+            ranked: false,
+            min_score: 0.0,
+            stack: Vec::new(),
+            born_from: None,
+            born_until: None,
+            sort: Default::default(),
+            after: None,
+            limit: 10,
+        };
+        repository.search_many(&query).await.unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn reads_queued_people_by_id_and_searches_them_once_written() {
+        let (repository, database) = repository(&[]).await;
+        let ana = person("ana");
+        repository.insert_many(slice::from_ref(&ana)).await.unwrap();
+
+        let found = repository.find_one(ana.id).await.unwrap().unwrap();
+        assert_eq!(found.nickname, ana.nickname);
+        assert_eq!(repository.count_people().await.unwrap(), 1);
+        assert_eq!(search(&repository, "ana").await, 0);
+        assert!(database.find_one(ana.id).await.unwrap().is_none());
+
+        repository.flush().await.unwrap();
+        assert_eq!(search(&repository, "ana").await, 1);
+        assert!(database.find_one(ana.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn rejects_nicknames_already_stored() {
+        let (repository, _) = repository(&[person("ana")]).await;
+
+        let err = repository.insert_many(&[person("ANA")]).await.unwrap_err();
+        assert!(err.is::<DuplicateNickname>());
+
+        let bia = person("bia");
+        let inserted = repository
+            .try_insert_many(&[person("ana"), bia.clone()])
+            .await
+            .unwrap();
+        assert_eq!(inserted, [bia.id]);
+    }
+
+    #[tokio::test]
+    async fn drops_queued_people_whose_nickname_was_stored_meanwhile() {
+        let (repository, database) = repository(&[]).await;
+        let queued = person("ana");
+        repository
+            .insert_many(slice::from_ref(&queued))
+            .await
+            .unwrap();
+
+        // by another replica
+        database.insert_many(&[person("ana")]).await.unwrap();
+        repository.flush().await.unwrap();
+
+        assert!(repository.find_one(queued.id).await.unwrap().is_none());
+        assert_eq!(repository.count_people().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn writes_a_queued_person_alone_before_changing_it() {
+        let (repository, database) = repository(&[]).await;
+        let (ana, bia) = (person("ana"), person("bia"));
+        repository
+            .insert_many(&[ana.clone(), bia.clone()])
+            .await
+            .unwrap();
+
+        let written = repository.update_one(&ana, Some(1)).await.unwrap();
+        assert!(matches!(written, Written::Applied { version: 2 }));
+        assert!(database.find_one(bia.id).await.unwrap().is_none());
+        assert_eq!(repository.count_people().await.unwrap(), 2);
+
+        let written = repository.delete_one(bia.id, None).await.unwrap();
+        assert!(matches!(written, Written::Applied { .. }));
+
+        // nothing left to write, ana was not written twice
+        repository.flush().await.unwrap();
+        assert_eq!(database.count_people().await.unwrap(), 1);
+        assert_eq!(repository.count_people().await.unwrap(), 1);
+        let found = database.find_one(ana.id).await.unwrap().unwrap();
+        assert_eq!(found.version, 2);
+    }
+
+    #[tokio::test]
+    async fn drains_the_queue_on_flush() {
+        let (repository, database) = repository(&[]).await;
+        let people: Vec<_> = (0..2500).map(|i| person(&format!("dev{i}"))).collect();
+        for chunk in people.chunks(100) {
+            repository.insert_many(chunk).await.unwrap();
+        }
+
+        repository.flush().await.unwrap();
+        assert_eq!(database.count_people().await.unwrap(), 2500);
+        assert_eq!(repository.count_people().await.unwrap(), 2500);
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    collections::HashSet,
+    hash::Hash,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use lru::LruCache;
+use uuid::Uuid;
+
+use crate::{config, domains::Person};
+
+use super::{PeopleRepository, PeopleStream, SearchHit, SearchQuery, SharedRepository, Written};
+
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// People kept in memory, least recently used ones are evicted first.
+    pub capacity: NonZeroUsize,
+    /// How long a person is served from memory, which bounds how long writes
+    /// made through other replicas go unnoticed.
+    pub ttl: Duration,
+    /// How long an unknown id keeps being answered as unknown.
+    pub negative_ttl: Duration,
+    /// Search queries kept in memory.
+    pub search_capacity: NonZeroUsize,
+    /// How long the results of a search are served from memory, which is how
+    /// long people inserted in the meantime may be missing from them. Updates
+    /// and deletes made through this replica drop every cached search.
+    pub search_ttl: Duration,
+    /// How often hit and miss counters are logged.
+    pub stats_interval: Duration,
+}
+
+impl CacheConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            capacity: config::env_or("CACHE_CAPACITY", default.capacity),
+            ttl: config::env_duration_ms("CACHE_TTL_MS", default.ttl),
+            negative_ttl: config::env_duration_ms("CACHE_NEGATIVE_TTL_MS", default.negative_ttl),
+            search_capacity: config::env_or("CACHE_SEARCH_CAPACITY", default.search_capacity),
+            search_ttl: config::env_duration_ms("CACHE_SEARCH_TTL_MS", default.search_ttl),
+            stats_interval: config::env_duration_ms(
+                "CACHE_STATS_INTERVAL_MS",
+                default.stats_interval,
+            ),
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: NonZeroUsize::new(10_000).unwrap(),
+            ttl: Duration::from_secs(1),
+            negative_ttl: Duration::from_millis(500),
+            search_capacity: NonZeroUsize::new(1_000).unwrap(),
+            search_ttl: Duration::from_millis(500),
+            stats_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+struct Entry<T> {
+    value: T,
+    expires_at: Instant,
+}
+
+impl<T> Entry<T> {
+    fn new(value: T, ttl: Duration) -> Self {
+        Self {
+            value,
+            expires_at: Instant::now() + ttl,
+        }
+    }
+}
+
+struct Entries<K, V> {
+    lru: LruCache<K, Entry<V>>,
+    /// Bumped by every write, so values read before one can tell they may
+    /// be stale.
+    generation: u64,
+}
+
+/// Bounded cache whose entries also expire after a while.
+struct Cache<K, V> {
+    entries: Mutex<Entries<K, V>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl<K: Hash + Eq, V: Clone> Cache<K, V> {
+    fn new(capacity: NonZeroUsize) -> Self {
+        let entries = Entries {
+            lru: LruCache::new(capacity),
+            generation: 0,
+        };
+        Self {
+            entries: Mutex::new(entries),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut entries.lru;
+        let value = match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        };
+
+        let counter = match value {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        value
+    }
+
+    fn generation(&self) -> u64 {
+        self.entries.lock().unwrap().generation
+    }
+
+    /// Caches a value read from the source, unless a write happened since
+    /// `generation`, as the value may predate it.
+    fn fill(&self, key: K, value: V, ttl: Duration, generation: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation == generation {
+            entries.lru.put(key, Entry::new(value, ttl));
+        }
+    }
+
+    /// Caches a value that was just written.
+    fn put(&self, key: K, value: V, ttl: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.lru.put(key, Entry::new(value, ttl));
+    }
+
+    fn remove(&self, key: &K) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.lru.pop(key);
+    }
+
+    fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.lru.clear();
+    }
+
+    fn stats(&self) -> (usize, usize) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Keeps recently read and written people in memory in front of another
+/// repository. Unknown ids are remembered for a short while too, and so are
+/// search results, which miss people inserted for up to
+/// [`CacheConfig::search_ttl`].
+///
+/// The cache belongs to a single replica: writes made through another one
+/// are only seen here once the cached person expires, which is why people
+/// are only kept for a short [`CacheConfig::ttl`].
+pub struct CachedPeopleRepository {
+    inner: SharedRepository,
+    config: CacheConfig,
+    people: Arc<Cache<Uuid, Option<Person>>>,
+    /// Keyed by the debug representation of the query.
+    searches: Arc<Cache<String, Vec<SearchHit>>>,
+}
+
+impl CachedPeopleRepository {
+    pub fn new(inner: SharedRepository, config: CacheConfig) -> Self {
+        let people = Arc::new(Cache::new(config.capacity));
+        let searches = Arc::new(Cache::new(config.search_capacity));
+        tokio::spawn(report_stats(
+            Arc::downgrade(&people),
+            Arc::downgrade(&searches),
+            config.stats_interval,
+        ));
+
+        Self {
+            inner,
+            people,
+            searches,
+            config,
+        }
+    }
+
+    fn remember(&self, person: &Person) {
+        self.people
+            .put(person.id, Some(person.clone()), self.config.ttl);
+    }
+}
+
+fn log_stats(people: &Cache<Uuid, Option<Person>>, searches: &Cache<String, Vec<SearchHit>>) {
+    let (hits, misses) = people.stats();
+    let (search_hits, search_misses) = searches.stats();
+    tracing::info!(
+        target: "cache",
+        hits,
+        misses,
+        search_hits,
+        search_misses,
+        "cache stats"
+    );
+}
+
+/// Logs the stats of the caches every `interval` until they are dropped.
+async fn report_stats(
+    people: Weak<Cache<Uuid, Option<Person>>>,
+    searches: Weak<Cache<String, Vec<SearchHit>>>,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let (Some(people), Some(searches)) = (people.upgrade(), searches.upgrade()) else {
+            return;
+        };
+        log_stats(&people, &searches);
+    }
+}
+
+#[async_trait::async_trait]
+impl PeopleRepository for CachedPeopleRepository {
+    async fn find_one(&self, id: Uuid) -> Result<Option<Person>> {
+        if let Some(person) = self.people.get(&id) {
+            return Ok(person);
+        }
+
+        let generation = self.people.generation();
+        let person = self.inner.find_one(id).await?;
+        let ttl = match person {
+            Some(_) => self.config.ttl,
+            None => self.config.negative_ttl,
+        };
+        self.people.fill(id, person.clone(), ttl, generation);
+
+        Ok(person)
+    }
+
+    async fn search_many(&self, query: &SearchQuery) -> Result<Vec<SearchHit>> {
+        let key = format!("{query:?}");
+        if let Some(hits) = self.searches.get(&key) {
+            return Ok(hits);
+        }
+
+        let generation = self.searches.generation();
+        let hits = self.inner.search_many(query).await?;
+        self.searches
+            .fill(key, hits.clone(), self.config.search_ttl, generation);
+
+        Ok(hits)
+    }
+
+    async fn insert_many(&self, people: &[Person]) -> Result<()> {
+        self.inner.insert_many(people).await?;
+        people.iter().for_each(|person| self.remember(person));
+
+        Ok(())
+    }
+
+    async fn try_insert_many(&self, people: &[Person]) -> Result<Vec<Uuid>> {
+        let inserted = self.inner.try_insert_many(people).await?;
+        let ids: HashSet<_> = inserted.iter().collect();
+        people
+            .iter()
+            .filter(|person| ids.contains(&person.id))
+            .for_each(|person| self.remember(person));
+
+        Ok(inserted)
+    }
+
+    async fn count_people(&self) -> Result<i64> {
+        self.inner.count_people().await
+    }
+
+    async fn list_nicknames(&self) -> Result<Vec<(String, Uuid)>> {
+        self.inner.list_nicknames().await
+    }
+
+    async fn update_one(&self, person: &Person, expected_version: Option<i32>) -> Result<Written> {
+        let written = self.inner.update_one(person, expected_version).await;
+        match written {
+            Ok(Written::Applied { version }) => self.remember(&Person {
+                version,
+                ..person.clone()
+            }),
+            _ => self.people.remove(&person.id),
+        }
+        self.searches.clear();
+
+        written
+    }
+
+    async fn delete_one(&self, id: Uuid, expected_version: Option<i32>) -> Result<Written> {
+        let written = self.inner.delete_one(id, expected_version).await;
+        self.people.remove(&id);
+        self.searches.clear();
+
+        written
+    }
+
+    async fn stream_all(&self) -> Result<PeopleStream> {
+        self.inner.stream_all().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        log_stats(&self.people, &self.searches);
+        self.inner.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use time::OffsetDateTime;
+
+    use crate::{
+        domains::{Birthday, Name, Nickname},
+        repositories::{memory::InMemoryPeopleRepository, SearchMode},
+    };
+
+    use super::*;
+
+    fn person(nickname: &str) -> Person {
+        Person {
+            id: Uuid::now_v7(),
+            name: Name::new("Ana").unwrap(),
+            nickname: Nickname::new(nickname).unwrap(),
+            birthday: Birthday::parse("2000-01-01").unwrap(),
+            stack: None,
+            updated_at: OffsetDateTime::now_utc(),
+            version: 1,
+        }
+    }
+
+    /// A cache that doesn't expire anything during a test, in front of the
+    /// database it reads from.
+    fn repository() -> (CachedPeopleRepository, SharedRepository) {
+        let database: SharedRepository = Arc::new(InMemoryPeopleRepository::default());
+        let config = CacheConfig {
+            ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(3600),
+            search_ttl: Duration::from_secs(3600),
+            stats_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+
+        (
+            CachedPeopleRepository::new(database.clone(), config),
+            database,
+        )
+    }
+
+    fn query(term: &str) -> SearchQuery {
+        SearchQuery {
+            term: term.to_string(),
+            mode: SearchMode::Trigram,
+            ranked: false,
+            min_score: 0.0,
+            stack: Vec::new(),
+            born_from: None,
+            born_until: None,
+            sort: Default::default(),
+            after: None,
+            limit: 10,
+        }
+    }
+
+    #[test]
+    fn skips_fills_older_than_a_write() {
+        let cache = Cache::new(NonZeroUsize::new(10).unwrap());
+        let ttl = Duration::from_secs(3600);
+
+        // read before the write, filled after it
+        let generation = cache.generation();
+        cache.put(1, "written", ttl);
+        cache.fill(1, "stale", ttl, generation);
+        assert_eq!(cache.get(&1), Some("written"));
+
+        let generation = cache.generation();
+        cache.remove(&2);
+        cache.fill(2, "stale", ttl, generation);
+        assert_eq!(cache.get(&2), None);
+
+        let generation = cache.generation();
+        cache.fill(3, "fresh", ttl, generation);
+        assert_eq!(cache.get(&3), Some("fresh"));
+    }
+
+    #[tokio::test]
+    async fn serves_people_from_memory_until_written_through_it() {
+        let (repository, database) = repository();
+        let ana = person("ana");
+        repository.insert_many(slice::from_ref(&ana)).await.unwrap();
+
+        // written behind the cache's back, as another replica would
+        let renamed = Person {
+            name: Name::new("Ana Souza").unwrap(),
+            ..ana.clone()
+        };
+        database.update_one(&renamed, None).await.unwrap();
+        let found = repository.find_one(ana.id).await.unwrap().unwrap();
+        assert_eq!(found.version, 1);
+
+        let written = repository.update_one(&renamed, None).await.unwrap();
+        assert!(matches!(written, Written::Applied { version: 3 }));
+        let found = repository.find_one(ana.id).await.unwrap().unwrap();
+        assert_eq!(found.version, 3);
+        assert_eq!(found.name.as_str(), "Ana Souza");
+    }
+
+    #[tokio::test]
+    async fn forgets_people_on_failed_updates_and_deletes() {
+        let (repository, _) = repository();
+        let ana = person("ana");
+        repository.insert_many(slice::from_ref(&ana)).await.unwrap();
+
+        let written = repository.update_one(&ana, Some(7)).await.unwrap();
+        assert!(matches!(written, Written::VersionMismatch));
+
+        repository.delete_one(ana.id, None).await.unwrap();
+        assert!(repository.find_one(ana.id).await.unwrap().is_none());
+
+        // remembered as unknown, until inserted through the cache
+        repository.insert_many(slice::from_ref(&ana)).await.unwrap();
+        assert!(repository.find_one(ana.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn drops_searches_on_updates_and_deletes() {
+        let (repository, _) = repository();
+        let ana = person("ana");
+        repository.insert_many(slice::from_ref(&ana)).await.unwrap();
+        let hits = repository.search_many(&query("ana")).await.unwrap();
+        assert_eq!(hits.len(), 1);
+
+        // inserts don't, for up to the search ttl
+        repository.insert_many(&[person("anabel")]).await.unwrap();
+        let hits = repository.search_many(&query("ana")).await.unwrap();
+        assert_eq!(hits.len(), 1);
+
+        repository.delete_one(ana.id, None).await.unwrap();
+        let hits = repository.search_many(&query("ana")).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].person.nickname.as_str(), "anabel");
+    }
+}
@@ -0,0 +1,358 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::Result;
+use bytes::Bytes;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{config, domains::Person, kv::SharedStore};
+
+use super::{
+    DuplicateNickname, PeopleRepository, PeopleStream, SearchHit, SearchQuery, SharedRepository,
+    Written,
+};
+
+/// A person as kept in the store, with the fields left out of the api
+/// representation.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Entry {
+    #[serde(flatten)]
+    person: Person,
+    #[serde(with = "time::serde::rfc3339")]
+    updated_at: OffsetDateTime,
+    version: i32,
+}
+
+/// Shares people and nickname reservations with other replicas through a
+/// [`KeyValueStore`](crate::kv::KeyValueStore), so a person created by one
+/// of them can be read from another, and two of them can't hand out the same
+/// nickname at once.
+///
+/// Reservations only guard writes in flight, and expire after a while so
+/// those left behind by a replica that died mid-write don't hold a nickname
+/// forever. Stored nicknames, including those stored before the store was,
+/// are held by the database constraint.
+pub struct KvPeopleRepository {
+    inner: SharedRepository,
+    store: SharedStore,
+    /// How long people are kept in the store after being written or read.
+    ttl: Duration,
+    /// How long a nickname stays reserved, which has to outlast the write it
+    /// is reserved for.
+    reservation_ttl: Duration,
+}
+
+impl KvPeopleRepository {
+    pub fn new(inner: SharedRepository, store: SharedStore) -> Self {
+        Self {
+            inner,
+            store,
+            ttl: config::env_duration_ms("KV_PERSON_TTL_MS", Duration::from_secs(300)),
+            reservation_ttl: config::env_duration_ms(
+                "KV_RESERVATION_TTL_MS",
+                Duration::from_secs(60),
+            ),
+        }
+    }
+
+    fn person_key(id: Uuid) -> String {
+        format!("person:{id}")
+    }
+
+    fn nickname_key(nickname: &str) -> String {
+        format!("nickname:{}", nickname.to_lowercase())
+    }
+
+    async fn share(&self, person: &Person) -> Result<()> {
+        let entry = Entry {
+            person: person.clone(),
+            updated_at: person.updated_at,
+            version: person.version,
+        };
+        let value = serde_json::to_vec(&entry)?;
+        self.store
+            .set(&Self::person_key(person.id), value.into(), Some(self.ttl))
+            .await
+    }
+
+    /// Reserves the nickname for `id`, which succeeds when it already holds it.
+    async fn reserve(&self, nickname: &str, id: Uuid) -> Result<bool> {
+        let key = Self::nickname_key(nickname);
+        let owner = Bytes::from(id.to_string());
+        let ttl = Some(self.reservation_ttl);
+        if self.store.set_nx(&key, owner.clone(), ttl).await? {
+            return Ok(true);
+        }
+
+        Ok(self.store.get(&key).await? == Some(owner))
+    }
+
+    async fn release(&self, nickname: &str) -> Result<()> {
+        self.store.delete(&Self::nickname_key(nickname)).await
+    }
+
+    async fn release_all<'a>(&self, people: impl Iterator<Item = &'a Person>) {
+        for person in people {
+            if let Err(err) = self.release(&person.nickname).await {
+                tracing::warn!(%err, nickname = person.nickname.as_str(), "failed to release nickname");
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PeopleRepository for KvPeopleRepository {
+    async fn find_one(&self, id: Uuid) -> Result<Option<Person>> {
+        // people stored before fields were checked don't read back, so they
+        // are looked up in the database every time
+        let value = self.store.get(&Self::person_key(id)).await?;
+        if let Some(Ok(entry)) = value.map(|value| serde_json::from_slice::<Entry>(&value)) {
+            return Ok(Some(Person {
+                updated_at: entry.updated_at,
+                version: entry.version,
+                ..entry.person
+            }));
+        }
+
+        let person = self.inner.find_one(id).await?;
+        if let Some(person) = &person {
+            self.share(person).await?;
+        }
+
+        Ok(person)
+    }
+
+    async fn search_many(&self, query: &SearchQuery) -> Result<Vec<SearchHit>> {
+        self.inner.search_many(query).await
+    }
+
+    async fn insert_many(&self, people: &[Person]) -> Result<()> {
+        for (reserved, person) in people.iter().enumerate() {
+            if !self.reserve(&person.nickname, person.id).await? {
+                self.release_all(people[..reserved].iter()).await;
+                return Err(DuplicateNickname.into());
+            }
+        }
+
+        if let Err(err) = self.inner.insert_many(people).await {
+            self.release_all(people.iter()).await;
+            return Err(err);
+        }
+
+        for person in people {
+            self.share(person).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn try_insert_many(&self, people: &[Person]) -> Result<Vec<Uuid>> {
+        let mut free = Vec::with_capacity(people.len());
+        for person in people {
+            if self.reserve(&person.nickname, person.id).await? {
+                free.push(person.clone());
+            }
+        }
+
+        let inserted = match self.inner.try_insert_many(&free).await {
+            Ok(inserted) => inserted,
+            Err(err) => {
+                self.release_all(free.iter()).await;
+                return Err(err);
+            }
+        };
+
+        let ids: HashSet<_> = inserted.iter().collect();
+        let (stored, skipped): (Vec<_>, Vec<_>) =
+            free.iter().partition(|person| ids.contains(&person.id));
+        self.release_all(skipped.into_iter()).await;
+        for person in stored {
+            self.share(person).await?;
+        }
+
+        Ok(inserted)
+    }
+
+    async fn count_people(&self) -> Result<i64> {
+        self.inner.count_people().await
+    }
+
+    async fn list_nicknames(&self) -> Result<Vec<(String, Uuid)>> {
+        self.inner.list_nicknames().await
+    }
+
+    async fn update_one(&self, person: &Person, expected_version: Option<i32>) -> Result<Written> {
+        let Some(current) = self.inner.find_one(person.id).await? else {
+            return Ok(Written::NotFound);
+        };
+
+        if !self.reserve(&person.nickname, person.id).await? {
+            return Err(DuplicateNickname.into());
+        }
+
+        let renamed = current.nickname.to_lowercase() != person.nickname.to_lowercase();
+        let written = match self.inner.update_one(person, expected_version).await {
+            Ok(written) => written,
+            Err(err) => {
+                if renamed {
+                    self.release(&person.nickname).await?;
+                }
+                return Err(err);
+            }
+        };
+
+        match written {
+            Written::Applied { version } => {
+                if renamed {
+                    self.release(&current.nickname).await?;
+                }
+                self.share(&Person {
+                    version,
+                    ..person.clone()
+                })
+                .await?;
+            }
+            _ if renamed => self.release(&person.nickname).await?,
+            _ => {}
+        }
+
+        Ok(written)
+    }
+
+    async fn delete_one(&self, id: Uuid, expected_version: Option<i32>) -> Result<Written> {
+        let current = self.inner.find_one(id).await?;
+        let written = self.inner.delete_one(id, expected_version).await?;
+        if let (Written::Applied { .. }, Some(current)) = (&written, current) {
+            self.store.delete(&Self::person_key(id)).await?;
+            self.release(&current.nickname).await?;
+        }
+
+        Ok(written)
+    }
+
+    async fn stream_all(&self) -> Result<PeopleStream> {
+        self.inner.stream_all().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{slice, sync::Arc};
+
+    use crate::{
+        domains::{Birthday, Name, Nickname},
+        kv::memory::MemoryStore,
+        repositories::memory::InMemoryPeopleRepository,
+    };
+
+    use super::*;
+
+    fn person(nickname: &str) -> Person {
+        Person {
+            id: Uuid::now_v7(),
+            name: Name::new("Ana").unwrap(),
+            nickname: Nickname::new(nickname).unwrap(),
+            birthday: Birthday::parse("2000-01-01").unwrap(),
+            stack: None,
+            updated_at: OffsetDateTime::now_utc(),
+            version: 1,
+        }
+    }
+
+    /// A replica with its own database sharing `store`.
+    fn replica(store: &SharedStore) -> KvPeopleRepository {
+        let inner = Arc::new(InMemoryPeopleRepository::default());
+        KvPeopleRepository::new(inner, store.clone())
+    }
+
+    #[tokio::test]
+    async fn shares_written_people() {
+        let store: SharedStore = Arc::new(MemoryStore::default());
+        let ana = person("ana");
+        replica(&store)
+            .insert_many(slice::from_ref(&ana))
+            .await
+            .unwrap();
+
+        // the other replica's database doesn't have it
+        let found = replica(&store).find_one(ana.id).await.unwrap().unwrap();
+        assert_eq!(found.nickname, ana.nickname);
+        assert_eq!(found.version, 1);
+    }
+
+    #[tokio::test]
+    async fn reserves_nicknames_across_replicas() {
+        let store: SharedStore = Arc::new(MemoryStore::default());
+        replica(&store).insert_many(&[person("ana")]).await.unwrap();
+
+        let err = replica(&store)
+            .insert_many(&[person("ANA")])
+            .await
+            .unwrap_err();
+        assert!(err.is::<DuplicateNickname>());
+
+        let inserted = replica(&store)
+            .try_insert_many(&[person("ana"), person("bia")])
+            .await
+            .unwrap();
+        assert_eq!(inserted.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn shares_updates_and_releases_old_nicknames() {
+        let store: SharedStore = Arc::new(MemoryStore::default());
+        let repository = replica(&store);
+        let ana = person("ana");
+        repository.insert_many(slice::from_ref(&ana)).await.unwrap();
+
+        let renamed = Person {
+            nickname: Nickname::new("bia").unwrap(),
+            ..ana.clone()
+        };
+        let written = repository.update_one(&renamed, Some(1)).await.unwrap();
+        assert!(matches!(written, Written::Applied { version: 2 }));
+
+        let found = replica(&store).find_one(ana.id).await.unwrap().unwrap();
+        assert_eq!(found.nickname.as_str(), "bia");
+        assert_eq!(found.version, 2);
+        replica(&store).insert_many(&[person("ana")]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lets_abandoned_reservations_expire() {
+        let store: SharedStore = Arc::new(MemoryStore::default());
+        let crashed = KvPeopleRepository {
+            reservation_ttl: Duration::from_millis(20),
+            ..replica(&store)
+        };
+        // reserved, but never written
+        assert!(crashed.reserve("ana", Uuid::now_v7()).await.unwrap());
+
+        let err = replica(&store)
+            .insert_many(&[person("ana")])
+            .await
+            .unwrap_err();
+        assert!(err.is::<DuplicateNickname>());
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        replica(&store).insert_many(&[person("ana")]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn forgets_deleted_people() {
+        let store: SharedStore = Arc::new(MemoryStore::default());
+        let repository = replica(&store);
+        let ana = person("ana");
+        repository.insert_many(slice::from_ref(&ana)).await.unwrap();
+
+        let written = repository.delete_one(ana.id, None).await.unwrap();
+        assert!(matches!(written, Written::Applied { .. }));
+
+        assert!(replica(&store).find_one(ana.id).await.unwrap().is_none());
+        replica(&store).insert_many(&[person("ana")]).await.unwrap();
+    }
+}
@@ -0,0 +1,421 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::RwLock,
+};
+
+use anyhow::Result;
+use uuid::Uuid;
+
+use crate::domains::Person;
+
+use super::{
+    fold_words, DuplicateNickname, PeopleRepository, PeopleStream, SearchHit, SearchMode,
+    SearchQuery, Written, SCORE_WEIGHTS, STREAM_BATCH_SIZE,
+};
+
+#[derive(Default)]
+struct People {
+    /// Ordered by id, so by creation time since ids are UUIDv7.
+    by_id: BTreeMap<Uuid, Person>,
+    /// Keyed by the lowercased nickname, which makes them unique regardless
+    /// of case like the `CITEXT` column does.
+    by_nickname: HashMap<String, Uuid>,
+}
+
+impl People {
+    fn is_taken(&self, nickname: &str, by: Uuid) -> bool {
+        self.by_nickname
+            .get(&nickname.to_lowercase())
+            .is_some_and(|owner| *owner != by)
+    }
+
+    fn insert(&mut self, person: Person) {
+        self.by_nickname
+            .insert(person.nickname.to_lowercase(), person.id);
+        self.by_id.insert(person.id, person);
+    }
+
+    fn remove(&mut self, id: Uuid) -> Option<Person> {
+        let person = self.by_id.remove(&id)?;
+        self.by_nickname.remove(&person.nickname.to_lowercase());
+        Some(person)
+    }
+}
+
+/// Weighs how well each field of a person `matches`, from 0 to 1, as a
+/// stand-in for the relevance databases compute.
+fn field_score(person: &Person, matches: impl Fn(&str) -> f32) -> f32 {
+    let [nickname, name, stack] = SCORE_WEIGHTS;
+    let items: Vec<&str> = person.stack.iter().flatten().map(|item| &**item).collect();
+
+    let score = nickname * matches(&person.nickname)
+        + name * matches(&person.name)
+        + stack * matches(&items.join(" "));
+
+    score / SCORE_WEIGHTS.iter().sum::<f32>()
+}
+
+/// Trigrams of the words of `text` the way `pg_trgm` extracts them,
+/// lowercased and padded with two spaces in front and one behind.
+fn trigrams(text: &str) -> HashSet<[char; 3]> {
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty());
+
+    let mut trigrams = HashSet::new();
+    for word in words {
+        let padded: Vec<char> = "  "
+            .chars()
+            .chain(word.to_lowercase().chars())
+            .chain([' '])
+            .collect();
+        trigrams.extend(padded.windows(3).map(|w| [w[0], w[1], w[2]]));
+    }
+
+    trigrams
+}
+
+/// Share of the trigrams of the term found in `text`, like `pg_trgm`'s
+/// `word_similarity` but without requiring them to be next to each other.
+fn word_similarity(term: &HashSet<[char; 3]>, text: &str) -> f32 {
+    if term.is_empty() {
+        return 0.0;
+    }
+
+    term.intersection(&trigrams(text)).count() as f32 / term.len() as f32
+}
+
+/// How similar the term is to any of the fields of `person`, which are kept
+/// apart unlike in the search term so words don't run into each other.
+fn similarity(person: &Person, term: &HashSet<[char; 3]>) -> f32 {
+    let stack = person.stack.iter().flatten().map(|item| &**item);
+    let fields: Vec<&str> = [&*person.nickname, &*person.name]
+        .into_iter()
+        .chain(stack)
+        .collect();
+
+    word_similarity(term, &fields.join(" "))
+}
+
+/// Whether some word of `text` starts with `word`, as full-text searches go
+/// without stemming.
+fn has_word(text: &str, word: &str) -> bool {
+    fold_words(text).any(|candidate| candidate.starts_with(word))
+}
+
+fn matches_filters(person: &Person, query: &SearchQuery) -> bool {
+    let has = |wanted: &String| {
+        person
+            .stack
+            .iter()
+            .flatten()
+            .any(|item| item.to_lowercase() == *wanted)
+    };
+
+    query.stack.iter().all(has)
+        && query.born_from.is_none_or(|from| *person.birthday >= from)
+        && query
+            .born_until
+            .is_none_or(|until| *person.birthday <= until)
+}
+
+/// Keeps people in the process memory, for running the api without a
+/// database. Nothing survives a restart.
+#[derive(Default)]
+pub struct InMemoryPeopleRepository {
+    people: RwLock<People>,
+}
+
+#[async_trait::async_trait]
+impl PeopleRepository for InMemoryPeopleRepository {
+    async fn find_one(&self, id: Uuid) -> Result<Option<Person>> {
+        Ok(self.people.read().unwrap().by_id.get(&id).cloned())
+    }
+
+    async fn search_many(&self, query: &SearchQuery) -> Result<Vec<SearchHit>> {
+        let words: Vec<_> = fold_words(&query.term).collect();
+        let term = trigrams(&query.term);
+        let matches = |person: &Person| match query.mode {
+            SearchMode::Trigram => {
+                person.search_term().contains(&query.term)
+                    || (query.ranked && similarity(person, &term) >= query.min_score)
+            }
+            SearchMode::FullText => {
+                !words.is_empty()
+                    && words.iter().all(|word| {
+                        has_word(&person.nickname, word)
+                            || has_word(&person.name, word)
+                            || person
+                                .stack
+                                .iter()
+                                .flatten()
+                                .any(|item| has_word(item, word))
+                    })
+            }
+        };
+        let score = |person: &Person| match query.mode {
+            SearchMode::Trigram => field_score(person, |field| word_similarity(&term, field)),
+            SearchMode::FullText => field_score(person, |field| {
+                match words.iter().any(|word| has_word(field, word)) {
+                    true => 1.0,
+                    false => 0.0,
+                }
+            }),
+        };
+
+        let people = self.people.read().unwrap();
+        let found = people.by_id.values().filter(|person| {
+            matches(person)
+                && matches_filters(person, query)
+                && query
+                    .after
+                    .is_none_or(|after| after.precedes(person, query.sort))
+        });
+
+        let mut scored: Vec<_> = found.map(|person| (score(person), person)).collect();
+        // stable, so ties stay in id order
+        if query.ranked {
+            scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        } else if query.sort.by_birthday() {
+            scored.sort_by_key(|(_, person)| person.birthday);
+        }
+        if !query.ranked && query.sort.descending() {
+            scored.reverse();
+        }
+
+        let hits = scored
+            .into_iter()
+            .take(query.limit)
+            .map(|(score, person)| SearchHit {
+                person: person.clone(),
+                score: query.ranked.then_some(score),
+            })
+            .collect();
+
+        Ok(hits)
+    }
+
+    async fn insert_many(&self, people: &[Person]) -> Result<()> {
+        let mut stored = self.people.write().unwrap();
+        let mut nicknames: Vec<_> = people
+            .iter()
+            .map(|person| person.nickname.to_lowercase())
+            .collect();
+        let taken = nicknames
+            .iter()
+            .any(|nickname| stored.by_nickname.contains_key(nickname));
+        nicknames.sort_unstable();
+        nicknames.dedup();
+        if taken || nicknames.len() != people.len() {
+            return Err(DuplicateNickname.into());
+        }
+
+        people
+            .iter()
+            .for_each(|person| stored.insert(person.clone()));
+
+        Ok(())
+    }
+
+    async fn try_insert_many(&self, people: &[Person]) -> Result<Vec<Uuid>> {
+        let mut stored = self.people.write().unwrap();
+        let mut inserted = Vec::with_capacity(people.len());
+        for person in people {
+            if !stored.is_taken(&person.nickname, person.id) {
+                stored.insert(person.clone());
+                inserted.push(person.id);
+            }
+        }
+
+        Ok(inserted)
+    }
+
+    async fn count_people(&self) -> Result<i64> {
+        Ok(self.people.read().unwrap().by_id.len() as i64)
+    }
+
+    async fn list_nicknames(&self) -> Result<Vec<(String, Uuid)>> {
+        let people = self.people.read().unwrap();
+        let nicknames = people
+            .by_id
+            .values()
+            .map(|person| (person.nickname.to_string(), person.id))
+            .collect();
+
+        Ok(nicknames)
+    }
+
+    async fn update_one(&self, person: &Person, expected_version: Option<i32>) -> Result<Written> {
+        let mut stored = self.people.write().unwrap();
+        let Some(current) = stored.by_id.get(&person.id) else {
+            return Ok(Written::NotFound);
+        };
+        if expected_version.is_some_and(|version| version != current.version) {
+            return Ok(Written::VersionMismatch);
+        }
+        if stored.is_taken(&person.nickname, person.id) {
+            return Err(DuplicateNickname.into());
+        }
+
+        let version = current.version + 1;
+        stored.remove(person.id);
+        stored.insert(Person {
+            version,
+            ..person.clone()
+        });
+
+        Ok(Written::Applied { version })
+    }
+
+    async fn delete_one(&self, id: Uuid, expected_version: Option<i32>) -> Result<Written> {
+        let mut stored = self.people.write().unwrap();
+        let Some(current) = stored.by_id.get(&id) else {
+            return Ok(Written::NotFound);
+        };
+        if expected_version.is_some_and(|version| version != current.version) {
+            return Ok(Written::VersionMismatch);
+        }
+
+        let version = current.version;
+        stored.remove(id);
+
+        Ok(Written::Applied { version })
+    }
+
+    async fn stream_all(&self) -> Result<PeopleStream> {
+        let people: Vec<_> = self
+            .people
+            .read()
+            .unwrap()
+            .by_id
+            .values()
+            .cloned()
+            .collect();
+        let batches: Vec<_> = people
+            .chunks(STREAM_BATCH_SIZE)
+            .map(|batch| Ok(batch.to_vec()))
+            .collect();
+
+        Ok(Box::pin(futures_util::stream::iter(batches)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use crate::domains::{Birthday, Name, Nickname, StackItem};
+
+    use super::*;
+
+    fn person(nickname: &str, name: &str, stack: &[&str]) -> Person {
+        Person {
+            id: Uuid::now_v7(),
+            name: Name::new(name).unwrap(),
+            nickname: Nickname::new(nickname).unwrap(),
+            birthday: Birthday::parse("2000-01-01").unwrap(),
+            stack: Some(
+                stack
+                    .iter()
+                    .map(|item| StackItem::new(*item).unwrap())
+                    .collect(),
+            ),
+            updated_at: OffsetDateTime::now_utc(),
+            version: 1,
+        }
+    }
+
+    async fn search(repository: &InMemoryPeopleRepository, term: &str) -> Vec<String> {
+        ranked_search(repository, SearchMode::FullText, term, None).await
+    }
+
+    /// Ranked when given the `min_score`.
+    async fn ranked_search(
+        repository: &InMemoryPeopleRepository,
+        mode: SearchMode,
+        term: &str,
+        min_score: Option<f32>,
+    ) -> Vec<String> {
+        let query = SearchQuery {
+            term: term.to_string(),
+            mode,
+            ranked: min_score.is_some(),
+            min_score: min_score.unwrap_or_default(),
+            stack: Vec::new(),
+            born_from: None,
+            born_until: None,
+            sort: Default::default(),
+            after: None,
+            limit: 10,
+        };
+        let hits = repository.search_many(&query).await.unwrap();
+        hits.into_iter()
+            .map(|hit| hit.person.nickname.to_string())
+            .collect()
+    }
+
+    async fn repository() -> InMemoryPeopleRepository {
+        let repository = InMemoryPeopleRepository::default();
+        let people = [
+            person("jp", "João Pedro", &["Rust"]),
+            person("dev", "Maria Desenvolvedora", &["Node.js"]),
+        ];
+        repository.insert_many(&people).await.unwrap();
+        repository
+    }
+
+    #[tokio::test]
+    async fn matches_full_text_regardless_of_accents_and_case() {
+        let repository = repository().await;
+        assert_eq!(search(&repository, "joao").await, ["jp"]);
+        assert_eq!(search(&repository, "JOÃO pedro").await, ["jp"]);
+        assert_eq!(search(&repository, "node").await, ["dev"]);
+    }
+
+    #[tokio::test]
+    async fn matches_full_text_words_as_prefixes() {
+        let repository = repository().await;
+        assert_eq!(search(&repository, "desenvolvedor").await, ["dev"]);
+        assert_eq!(search(&repository, "ped").await, ["jp"]);
+        // every word has to match, and only from its start
+        assert!(search(&repository, "joao maria").await.is_empty());
+        assert!(search(&repository, "edro").await.is_empty());
+        assert!(search(&repository, "--").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ranks_nicknames_over_names_over_stacks() {
+        let repository = InMemoryPeopleRepository::default();
+        // inserted the other way around, so ids can't explain the order
+        let people = [
+            person("cris", "Cristina", &["Rust"]),
+            person("bia", "Rust Souza", &["Go"]),
+            person("rust", "Ana Lima", &["Go"]),
+        ];
+        repository.insert_many(&people).await.unwrap();
+
+        for mode in [SearchMode::Trigram, SearchMode::FullText] {
+            let found = ranked_search(&repository, mode, "rust", Some(0.3)).await;
+            assert_eq!(found, ["rust", "bia", "cris"], "{mode:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_similar_people_above_the_threshold() {
+        let repository = InMemoryPeopleRepository::default();
+        let people = [
+            // 3 of the 5 trigrams of `rust`
+            person("rusk", "Ana", &[]),
+            // 2 of them
+            person("zust", "Bia", &[]),
+        ];
+        repository.insert_many(&people).await.unwrap();
+        let search = |min_score| ranked_search(&repository, SearchMode::Trigram, "rust", min_score);
+
+        assert_eq!(search(Some(0.5)).await, ["rusk"]);
+        assert_eq!(search(Some(0.3)).await, ["rusk", "zust"]);
+        assert!(search(Some(0.7)).await.is_empty());
+        // only ranked searches look past the term itself
+        assert!(search(None).await.is_empty());
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use anyhow::Result;
+use uuid::Uuid;
+
+use crate::domains::Person;
+
+use super::{
+    DuplicateNickname, PeopleRepository, PeopleStream, SearchHit, SearchQuery, SharedRepository,
+    Written,
+};
+
+/// Rejects taken nicknames without a round trip to the database, keeping
+/// every known nickname in memory. Like the `CITEXT` column, they are compared
+/// case-insensitively.
+///
+/// Only writes made through this instance are seen after the warm-up, so the
+/// database constraint still has the final word.
+pub struct NicknameIndexRepository {
+    inner: SharedRepository,
+    index: RwLock<Index>,
+}
+
+#[derive(Default)]
+struct Index {
+    /// Who holds each nickname, whether stored or only reserved, keyed by
+    /// the lowercased nickname.
+    owners: HashMap<String, Uuid>,
+    /// The other way around, so owners can be forgotten one by one.
+    nicknames: HashMap<Uuid, String>,
+}
+
+impl Index {
+    fn owner(&self, nickname: &str) -> Option<Uuid> {
+        self.owners.get(nickname).copied()
+    }
+
+    /// Gives `nickname` to `id`, dropping the one it held before.
+    fn claim(&mut self, nickname: String, id: Uuid) {
+        self.forget(id);
+        self.owners.insert(nickname.clone(), id);
+        self.nicknames.insert(id, nickname);
+    }
+
+    /// Drops `nickname` if `id` still holds it.
+    fn release(&mut self, nickname: &str, id: Uuid) {
+        if self.owner(nickname) == Some(id) {
+            self.owners.remove(nickname);
+            self.nicknames.remove(&id);
+        }
+    }
+
+    fn forget(&mut self, id: Uuid) {
+        if let Some(nickname) = self.nicknames.remove(&id) {
+            self.owners.remove(&nickname);
+        }
+    }
+}
+
+impl NicknameIndexRepository {
+    /// Builds the index from every nickname already stored in `inner`.
+    pub async fn warm(inner: SharedRepository) -> Result<Self> {
+        let mut index = Index::default();
+        for (nickname, id) in inner.list_nicknames().await? {
+            index.claim(nickname.to_lowercase(), id);
+        }
+        tracing::info!(nicknames = index.owners.len(), "nickname index warmed");
+
+        Ok(Self {
+            inner,
+            index: RwLock::new(index),
+        })
+    }
+
+    /// Reserves the nicknames of `people` for them, skipping those taken by
+    /// someone else, or with `all` failing unless every one is free. People
+    /// already holding their nickname keep it. Returns the people whose
+    /// nickname is now theirs.
+    pub(super) fn reserve<'a>(&self, people: &'a [Person], all: bool) -> Result<Vec<&'a Person>> {
+        let mut index = self.index.write().unwrap();
+        let mut reserved = Vec::with_capacity(people.len());
+        let mut taken: Vec<(String, Uuid)> = Vec::new();
+        for person in people {
+            // also catches nicknames repeated within `people`
+            let nickname = person.nickname.to_lowercase();
+            match index.owner(&nickname) {
+                Some(owner) if owner == person.id => {}
+                Some(_) if all => {
+                    for (nickname, id) in taken {
+                        index.release(&nickname, id);
+                    }
+                    return Err(DuplicateNickname.into());
+                }
+                Some(_) => continue,
+                None => {
+                    index.claim(nickname.clone(), person.id);
+                    taken.push((nickname, person.id));
+                }
+            }
+            reserved.push(person);
+        }
+
+        Ok(reserved)
+    }
+
+    /// Stores people whose nicknames were already reserved, releasing those
+    /// the database turned down. They stay reserved when the write fails, so
+    /// it can be tried again.
+    pub(super) async fn insert_reserved(&self, people: &[Person]) -> Result<Vec<Uuid>> {
+        let inserted = self.inner.try_insert_many(people).await?;
+        let ids: HashSet<_> = inserted.iter().collect();
+        self.release(people.iter().filter(|person| !ids.contains(&person.id)));
+
+        Ok(inserted)
+    }
+
+    /// Releases the nicknames of `people` reserved before a failed write.
+    fn release<'a>(&self, people: impl Iterator<Item = &'a Person>) {
+        let mut index = self.index.write().unwrap();
+        for person in people {
+            index.release(&person.nickname.to_lowercase(), person.id);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PeopleRepository for NicknameIndexRepository {
+    async fn find_one(&self, id: Uuid) -> Result<Option<Person>> {
+        self.inner.find_one(id).await
+    }
+
+    async fn search_many(&self, query: &SearchQuery) -> Result<Vec<SearchHit>> {
+        self.inner.search_many(query).await
+    }
+
+    async fn insert_many(&self, people: &[Person]) -> Result<()> {
+        self.reserve(people, true)?;
+
+        let inserted = self.inner.insert_many(people).await;
+        if inserted.is_err() {
+            self.release(people.iter());
+        }
+
+        inserted
+    }
+
+    async fn try_insert_many(&self, people: &[Person]) -> Result<Vec<Uuid>> {
+        let free: Vec<_> = self.reserve(people, false)?.into_iter().cloned().collect();
+
+        let inserted = self.insert_reserved(&free).await;
+        if inserted.is_err() {
+            self.release(free.iter());
+        }
+
+        inserted
+    }
+
+    async fn count_people(&self) -> Result<i64> {
+        self.inner.count_people().await
+    }
+
+    async fn list_nicknames(&self) -> Result<Vec<(String, Uuid)>> {
+        self.inner.list_nicknames().await
+    }
+
+    async fn update_one(&self, person: &Person, expected_version: Option<i32>) -> Result<Written> {
+        let nickname = person.nickname.to_lowercase();
+        let owner = self.index.read().unwrap().owner(&nickname);
+        if owner.is_some_and(|owner| owner != person.id) {
+            return Err(DuplicateNickname.into());
+        }
+
+        let written = self.inner.update_one(person, expected_version).await?;
+        if let Written::Applied { .. } = written {
+            self.index.write().unwrap().claim(nickname, person.id);
+        }
+
+        Ok(written)
+    }
+
+    async fn delete_one(&self, id: Uuid, expected_version: Option<i32>) -> Result<Written> {
+        let written = self.inner.delete_one(id, expected_version).await?;
+        if let Written::Applied { .. } = written {
+            self.index.write().unwrap().forget(id);
+        }
+
+        Ok(written)
+    }
+
+    async fn stream_all(&self) -> Result<PeopleStream> {
+        self.inner.stream_all().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{slice, sync::Arc};
+
+    use time::OffsetDateTime;
+
+    use crate::{
+        domains::{Birthday, Name, Nickname},
+        repositories::memory::InMemoryPeopleRepository,
+    };
+
+    use super::*;
+
+    fn person(nickname: &str) -> Person {
+        Person {
+            id: Uuid::now_v7(),
+            name: Name::new("Ana").unwrap(),
+            nickname: Nickname::new(nickname).unwrap(),
+            birthday: Birthday::parse("2000-01-01").unwrap(),
+            stack: None,
+            updated_at: OffsetDateTime::now_utc(),
+            version: 1,
+        }
+    }
+
+    async fn repository() -> (NicknameIndexRepository, SharedRepository) {
+        let database: SharedRepository = Arc::new(InMemoryPeopleRepository::default());
+        database.insert_many(&[person("Stored")]).await.unwrap();
+        let index = NicknameIndexRepository::warm(database.clone())
+            .await
+            .unwrap();
+
+        (index, database)
+    }
+
+    fn owner(repository: &NicknameIndexRepository, nickname: &str) -> Option<Uuid> {
+        repository.index.read().unwrap().owner(nickname)
+    }
+
+    #[test]
+    fn claims_forget_the_previous_nickname() {
+        let mut index = Index::default();
+        let id = Uuid::now_v7();
+        index.claim("ana".into(), id);
+        index.claim("bia".into(), id);
+
+        assert_eq!(index.owner("ana"), None);
+        assert_eq!(index.owner("bia"), Some(id));
+
+        // only the owner releases a nickname
+        index.release("bia", Uuid::now_v7());
+        assert_eq!(index.owner("bia"), Some(id));
+        index.forget(id);
+        assert_eq!(index.owner("bia"), None);
+    }
+
+    #[tokio::test]
+    async fn warms_up_with_stored_nicknames() {
+        let (repository, _) = repository().await;
+        assert!(owner(&repository, "stored").is_some());
+
+        let err = repository
+            .insert_many(&[person("STORED")])
+            .await
+            .unwrap_err();
+        assert!(err.is::<DuplicateNickname>());
+    }
+
+    #[tokio::test]
+    async fn releases_nicknames_of_failed_inserts() {
+        let (repository, database) = repository().await;
+        // stored by another replica, unknown to the index
+        database.insert_many(&[person("ana")]).await.unwrap();
+
+        let err = repository
+            .insert_many(&[person("bia"), person("ana")])
+            .await
+            .unwrap_err();
+        assert!(err.is::<DuplicateNickname>());
+        assert_eq!(owner(&repository, "ana"), None);
+        assert_eq!(owner(&repository, "bia"), None);
+
+        let cris = person("cris");
+        let inserted = repository
+            .try_insert_many(&[person("ana"), cris.clone()])
+            .await
+            .unwrap();
+        assert_eq!(inserted, [cris.id]);
+        assert_eq!(owner(&repository, "ana"), None);
+        assert_eq!(owner(&repository, "cris"), Some(cris.id));
+    }
+
+    #[tokio::test]
+    async fn frees_nicknames_on_rename_and_delete() {
+        let (repository, _) = repository().await;
+        let ana = person("ana");
+        repository.insert_many(slice::from_ref(&ana)).await.unwrap();
+
+        let renamed = Person {
+            nickname: Nickname::new("Bia").unwrap(),
+            ..ana.clone()
+        };
+        repository.update_one(&renamed, None).await.unwrap();
+        assert_eq!(owner(&repository, "ana"), None);
+        assert_eq!(owner(&repository, "bia"), Some(ana.id));
+
+        repository.delete_one(ana.id, None).await.unwrap();
+        assert_eq!(owner(&repository, "bia"), None);
+        repository.insert_many(&[person("bia")]).await.unwrap();
+    }
+}
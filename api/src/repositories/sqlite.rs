@@ -0,0 +1,545 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use futures_util::TryStreamExt;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    types::Json,
+    QueryBuilder, Sqlite, SqlitePool,
+};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::domains::{Birthday, Name, Nickname, Person, StackItem};
+
+use super::{
+    fold_words, spawn_stream, PeopleRepository, PeopleStream, SearchHit, SearchMode, SearchQuery,
+    Written, SCORE_WEIGHTS, STREAM_BATCH_SIZE,
+};
+
+const COLUMNS: &str = "id, name, nickname, birthday, stack, updated_at, version";
+
+#[derive(sqlx::FromRow)]
+struct PersonRow {
+    id: Uuid,
+    name: Name,
+    nickname: Nickname,
+    birthday: Birthday,
+    /// Kept as text, as deserializing stack items would check them.
+    stack: Option<Json<Vec<String>>>,
+    updated_at: OffsetDateTime,
+    version: i32,
+}
+
+#[derive(sqlx::FromRow)]
+struct SearchRow {
+    #[sqlx(flatten)]
+    person: PersonRow,
+    score: Option<f32>,
+}
+
+impl From<PersonRow> for Person {
+    fn from(row: PersonRow) -> Self {
+        Self {
+            id: row.id,
+            name: row.name,
+            nickname: row.nickname,
+            birthday: row.birthday,
+            stack: row
+                .stack
+                .map(|stack| stack.0.into_iter().map(StackItem::new_unchecked).collect()),
+            updated_at: row.updated_at,
+            version: row.version,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlitePeopleRepository {
+    pool: SqlitePool,
+}
+
+impl SqlitePeopleRepository {
+    /// Opens the database at `url`, like `sqlite://people.db`, creating it
+    /// when missing.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+
+        Ok(Self { pool })
+    }
+
+    pub async fn migrate(&self) -> Result<()> {
+        sqlx::migrate!("./migrations/sqlite")
+            .run(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn missed_write(&self, id: Uuid) -> Result<Written> {
+        let exists: Option<(i32,)> = sqlx::query_as("SELECT version FROM people WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(match exists {
+            Some(_) => Written::VersionMismatch,
+            None => Written::NotFound,
+        })
+    }
+}
+
+fn insert_query(people: &[Person]) -> QueryBuilder<'_, Sqlite> {
+    let mut query = QueryBuilder::new(
+        "INSERT INTO people \
+        (id, name, nickname, birthday, stack, updated_at, version, search_term, nickname_key)",
+    );
+    query.push_values(people, |mut query, person| {
+        query
+            .push_bind(person.id)
+            .push_bind(&person.name)
+            .push_bind(&person.nickname)
+            .push_bind(person.birthday)
+            .push_bind(person.stack.as_ref().map(Json))
+            .push_bind(person.updated_at)
+            .push_bind(person.version)
+            .push_bind(person.search_term())
+            .push_bind(person.nickname.to_lowercase());
+    });
+
+    query
+}
+
+/// `instr` is case-sensitive like postgres' `LIKE`, unlike sqlite's, and
+/// without trigrams people are ranked by which fields contain the term.
+/// Full-text searches go through the `people_search` fts5 table instead.
+fn search_query(query: &SearchQuery) -> QueryBuilder<'_, Sqlite> {
+    let mut sql = QueryBuilder::new(format!("SELECT {COLUMNS}, "));
+    let [nickname, name, stack] = SCORE_WEIGHTS;
+    match (query.mode, query.ranked) {
+        (SearchMode::Trigram, true) => {
+            let total: f32 = SCORE_WEIGHTS.iter().sum();
+            sql.push("(")
+                .push_bind(nickname)
+                .push(" * (instr(lower(nickname), ")
+                .push_bind(&query.term)
+                .push(") > 0) + ")
+                .push_bind(name)
+                .push(" * (instr(lower(name), ")
+                .push_bind(&query.term)
+                .push(") > 0) + ")
+                .push_bind(stack)
+                .push(" * (instr(lower(coalesce(stack, '')), ")
+                .push_bind(&query.term)
+                .push(") > 0)) / ")
+                .push_bind(total)
+                .push(" AS score");
+        }
+        // bm25 goes from 0 down, the better the match the lower
+        (SearchMode::FullText, true) => {
+            sql.push("-match_rank / (1 - match_rank) AS score");
+        }
+        (_, false) => {
+            sql.push("NULL AS score");
+        }
+    }
+
+    match query.mode {
+        SearchMode::Trigram => {
+            sql.push(" FROM people WHERE instr(search_term, ")
+                .push_bind(&query.term)
+                .push(") > 0");
+        }
+        SearchMode::FullText => {
+            // every word as a quoted prefix, as fts5 has no portuguese stemmer
+            let words: Vec<_> = fold_words(&query.term)
+                .map(|word| format!("\"{word}\"*"))
+                .collect();
+            sql.push(" FROM people, (SELECT id AS match_id, bm25(people_search, 0, ")
+                .push_bind(nickname)
+                .push(", ")
+                .push_bind(name)
+                .push(", ")
+                .push_bind(stack)
+                .push(") AS match_rank FROM people_search WHERE people_search MATCH ")
+                .push_bind(words.join(" "))
+                .push(") WHERE id = match_id");
+        }
+    }
+
+    for item in &query.stack {
+        sql.push(" AND EXISTS (SELECT 1 FROM json_each(people.stack) WHERE lower(value) = ")
+            .push_bind(item)
+            .push(")");
+    }
+    if let Some(from) = query.born_from {
+        sql.push(" AND birthday >= ").push_bind(from);
+    }
+    if let Some(until) = query.born_until {
+        sql.push(" AND birthday <= ").push_bind(until);
+    }
+    if let Some(after) = query.after {
+        let op = if query.sort.descending() { "<" } else { ">" };
+        match after.birthday {
+            Some(birthday) => sql
+                .push(format_args!(" AND (birthday, id) {op} ("))
+                .push_bind(birthday)
+                .push(", ")
+                .push_bind(after.id)
+                .push(")"),
+            None => sql.push(format_args!(" AND id {op} ")).push_bind(after.id),
+        };
+    }
+
+    let order_by = match query.ranked {
+        true => "score DESC, id",
+        false => query.sort.order_by(),
+    };
+    sql.push(format_args!(" ORDER BY {order_by} LIMIT "))
+        .push_bind(query.limit as i64);
+
+    sql
+}
+
+#[async_trait::async_trait]
+impl PeopleRepository for SqlitePeopleRepository {
+    async fn find_one(&self, id: Uuid) -> Result<Option<Person>> {
+        let row: Option<PersonRow> =
+            sqlx::query_as(&format!("SELECT {COLUMNS} FROM people WHERE id = ?"))
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(row.map(Into::into))
+    }
+
+    async fn search_many(&self, query: &SearchQuery) -> Result<Vec<SearchHit>> {
+        // fts5 rejects empty queries rather than matching nothing
+        if query.mode == SearchMode::FullText && fold_words(&query.term).next().is_none() {
+            return Ok(Vec::new());
+        }
+
+        let rows: Vec<SearchRow> = search_query(query)
+            .build_query_as()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| SearchHit {
+                person: row.person.into(),
+                score: row.score,
+            })
+            .collect())
+    }
+
+    async fn insert_many(&self, people: &[Person]) -> Result<()> {
+        if people.is_empty() {
+            return Ok(());
+        }
+
+        insert_query(people).build().execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn try_insert_many(&self, people: &[Person]) -> Result<Vec<Uuid>> {
+        if people.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let inserted: Vec<(Uuid,)> = insert_query(people)
+            .push(" ON CONFLICT DO NOTHING RETURNING id")
+            .build_query_as()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(inserted.into_iter().map(|(id,)| id).collect())
+    }
+
+    async fn count_people(&self) -> Result<i64> {
+        let (rows,) = sqlx::query_as("SELECT COUNT(1) FROM people")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(rows)
+    }
+
+    async fn list_nicknames(&self) -> Result<Vec<(String, Uuid)>> {
+        sqlx::query_as("SELECT nickname, id FROM people")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(Into::into)
+    }
+
+    async fn update_one(&self, person: &Person, expected_version: Option<i32>) -> Result<Written> {
+        let version: Option<(i32,)> = sqlx::query_as(
+            "\
+UPDATE people SET \
+    name = ?2, \
+    nickname = ?3, \
+    birthday = ?4, \
+    stack = ?5, \
+    updated_at = ?6, \
+    search_term = ?7, \
+    nickname_key = ?9, \
+    version = version + 1 \
+WHERE id = ?1 AND (?8 IS NULL OR version = ?8) \
+RETURNING version\
+",
+        )
+        .bind(person.id)
+        .bind(&person.name)
+        .bind(&person.nickname)
+        .bind(person.birthday)
+        .bind(person.stack.as_ref().map(Json))
+        .bind(person.updated_at)
+        .bind(person.search_term())
+        .bind(expected_version)
+        .bind(person.nickname.to_lowercase())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match version {
+            Some((version,)) => Ok(Written::Applied { version }),
+            None => self.missed_write(person.id).await,
+        }
+    }
+
+    async fn delete_one(&self, id: Uuid, expected_version: Option<i32>) -> Result<Written> {
+        let version: Option<(i32,)> = sqlx::query_as(
+            "DELETE FROM people WHERE id = ?1 AND (?2 IS NULL OR version = ?2) RETURNING version",
+        )
+        .bind(id)
+        .bind(expected_version)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match version {
+            Some((version,)) => Ok(Written::Applied { version }),
+            None => self.missed_write(id).await,
+        }
+    }
+
+    async fn stream_all(&self) -> Result<PeopleStream> {
+        let pool = self.pool.clone();
+
+        Ok(spawn_stream(|sender| async move {
+            // rows are stepped through one by one as the batches fill up
+            let sql = format!("SELECT {COLUMNS} FROM people ORDER BY id");
+            let mut rows = sqlx::query_as::<_, PersonRow>(&sql).fetch(&pool);
+            let mut batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+            while let Some(row) = rows.try_next().await? {
+                batch.push(row.into());
+                if batch.len() == STREAM_BATCH_SIZE {
+                    let full = std::mem::replace(&mut batch, Vec::with_capacity(STREAM_BATCH_SIZE));
+                    if sender.send(Ok(full)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            if !batch.is_empty() {
+                let _ = sender.send(Ok(batch)).await;
+            }
+
+            Ok(())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use crate::{error::ApiError, repositories::DuplicateNickname};
+
+    use super::*;
+
+    fn person(nickname: &str, name: &str, stack: &[&str]) -> Person {
+        Person {
+            id: Uuid::now_v7(),
+            name: Name::new(name).unwrap(),
+            nickname: Nickname::new(nickname).unwrap(),
+            birthday: Birthday::parse("2000-01-01").unwrap(),
+            stack: Some(
+                stack
+                    .iter()
+                    .map(|item| StackItem::new(*item).unwrap())
+                    .collect(),
+            ),
+            updated_at: OffsetDateTime::now_utc(),
+            version: 1,
+        }
+    }
+
+    async fn repository() -> SqlitePeopleRepository {
+        let repository = SqlitePeopleRepository::connect("sqlite::memory:")
+            .await
+            .unwrap();
+        repository.migrate().await.unwrap();
+        repository
+    }
+
+    async fn search(
+        repository: &SqlitePeopleRepository,
+        mode: SearchMode,
+        term: &str,
+    ) -> Vec<String> {
+        let query = SearchQuery {
+            term: term.to_string(),
+            mode,
+            ranked: true,
+            min_score: 0.0,
+            stack: Vec::new(),
+            born_from: None,
+            born_until: None,
+            sort: Default::default(),
+            after: None,
+            limit: 10,
+        };
+        let hits = repository.search_many(&query).await.unwrap();
+        hits.into_iter()
+            .map(|hit| hit.person.nickname.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn migrates_more_than_once() {
+        let repository = repository().await;
+        repository.migrate().await.unwrap();
+        assert_eq!(repository.count_people().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn reads_back_inserted_people() {
+        let repository = repository().await;
+        let jp = person("jp", "João Pedro", &["Rust", "SQLite"]);
+        repository.insert_many(slice::from_ref(&jp)).await.unwrap();
+
+        let found = repository.find_one(jp.id).await.unwrap().unwrap();
+        assert_eq!(found.name, jp.name);
+        assert_eq!(found.nickname, jp.nickname);
+        assert_eq!(found.birthday, jp.birthday);
+        assert_eq!(found.stack, jp.stack);
+        assert_eq!(found.version, 1);
+        assert!(repository.find_one(Uuid::now_v7()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn searches_through_fts5_and_substrings() {
+        let repository = repository().await;
+        let people = [
+            person("jp", "João Pedro", &["Rust"]),
+            person("dev", "Maria Desenvolvedora", &["Node.js"]),
+            person("rusty", "Ana", &["Go"]),
+        ];
+        repository.insert_many(&people).await.unwrap();
+
+        assert_eq!(
+            search(&repository, SearchMode::FullText, "JOAO").await,
+            ["jp"]
+        );
+        assert_eq!(
+            search(&repository, SearchMode::FullText, "desenvolv").await,
+            ["dev"]
+        );
+        assert!(search(&repository, SearchMode::FullText, "edro")
+            .await
+            .is_empty());
+        assert!(search(&repository, SearchMode::FullText, " - ")
+            .await
+            .is_empty());
+        // ranked by the field that matched
+        assert_eq!(
+            search(&repository, SearchMode::Trigram, "rust").await,
+            ["rusty", "jp"]
+        );
+
+        // the fts5 table follows updates and deletes
+        let renamed = Person {
+            name: Name::new("Joana").unwrap(),
+            ..people[0].clone()
+        };
+        repository.update_one(&renamed, Some(1)).await.unwrap();
+        assert!(search(&repository, SearchMode::FullText, "joao")
+            .await
+            .is_empty());
+        assert_eq!(
+            search(&repository, SearchMode::FullText, "joana").await,
+            ["jp"]
+        );
+        repository.delete_one(people[0].id, None).await.unwrap();
+        assert!(search(&repository, SearchMode::FullText, "joana")
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_nicknames_unique_regardless_of_case() {
+        let repository = repository().await;
+        repository
+            .insert_many(&[person("joão", "João", &[])])
+            .await
+            .unwrap();
+
+        for nickname in ["JOÃO", "João"] {
+            let err = repository
+                .insert_many(&[person(nickname, "João", &[])])
+                .await
+                .unwrap_err();
+            assert!(!err.is::<DuplicateNickname>());
+            assert!(matches!(ApiError::from(err), ApiError::NicknameTaken));
+        }
+
+        let bia = person("bia", "Bia", &[]);
+        let inserted = repository
+            .try_insert_many(&[person("JOÃO", "João", &[]), bia.clone()])
+            .await
+            .unwrap();
+        assert_eq!(inserted, [bia.id]);
+
+        let renamed = Person {
+            nickname: Nickname::new("JOÃO").unwrap(),
+            ..bia
+        };
+        assert!(repository.update_one(&renamed, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn guards_writes_with_versions() {
+        let repository = repository().await;
+        let ana = person("ana", "Ana", &[]);
+        repository.insert_many(slice::from_ref(&ana)).await.unwrap();
+
+        let written = repository.update_one(&ana, Some(1)).await.unwrap();
+        assert!(matches!(written, Written::Applied { version: 2 }));
+        let written = repository.update_one(&ana, Some(1)).await.unwrap();
+        assert!(matches!(written, Written::VersionMismatch));
+        let written = repository.delete_one(Uuid::now_v7(), None).await.unwrap();
+        assert!(matches!(written, Written::NotFound));
+        let written = repository.delete_one(ana.id, Some(2)).await.unwrap();
+        assert!(matches!(written, Written::Applied { version: 2 }));
+    }
+
+    #[tokio::test]
+    async fn tells_id_conflicts_from_nickname_ones() {
+        let repository = repository().await;
+        let ana = person("ana", "Ana", &[]);
+        repository.insert_many(slice::from_ref(&ana)).await.unwrap();
+
+        let same_id = Person {
+            nickname: Nickname::new("bia").unwrap(),
+            ..ana
+        };
+        let err = repository.insert_many(&[same_id]).await.unwrap_err();
+        assert!(matches!(ApiError::from(err), ApiError::PersonExists));
+
+        let err = repository
+            .insert_many(&[person("ana", "Ana", &[])])
+            .await
+            .unwrap_err();
+        assert!(matches!(ApiError::from(err), ApiError::NicknameTaken));
+    }
+}
@@ -1,158 +1,448 @@
 // use std::net::ToSocketAddrs;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 use std::{future::Future, io, net::SocketAddr, sync::Arc};
 
-use crate::http::codec::ConnectionCodec;
-use crate::http::{Request, Response};
+use crate::config;
+use crate::error::{ApiError, RequestError, ResponseError};
+use crate::http::codec::{ConnectionCodec, Limits};
+use crate::http::{Chunk, Handler, IntoResponse, Request, Response, CHUNKED, CLOSE, KEEP_ALIVE};
 use futures_util::{SinkExt, StreamExt};
-use http::header::USER_AGENT;
-use http::{header::CONNECTION, HeaderValue};
+use http::header::{RETRY_AFTER, TRANSFER_ENCODING};
+use http::{header::CONNECTION, Version};
 use tokio::net::ToSocketAddrs;
 use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::MissedTickBehavior;
 use tokio::{net::TcpStream, sync::Semaphore};
-use tokio_util::codec::Decoder;
+use tokio_util::codec::{Decoder, Framed};
+use tokio_util::sync::CancellationToken;
 
-type Handler<A, F> = fn(Request, A) -> F;
+/// Shortest [`ServerConfig::metrics_interval`], as `interval` panics on zero.
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
 
-pub struct Server<A, F> {
-    state: A,
-    handler: Handler<A, F>,
+pub struct Server<S, H> {
+    state: S,
+    handler: H,
     semaphore: Arc<Semaphore>,
+    config: ServerConfig,
+    stop: CancellationToken,
+    metrics: Metrics,
 }
 
-const PERMITS: usize = 1_000;
+/// Counters since the server started, logged every
+/// [`ServerConfig::metrics_interval`] and once more on shutdown.
+#[derive(Default)]
+struct Metrics {
+    accepted: AtomicUsize,
+    /// Requests handled, not counting shed ones.
+    served: AtomicUsize,
+    /// Requests waiting for a permit right now.
+    waiting: AtomicUsize,
+    /// Requests answered with 503 because the queue was full.
+    shed_queue_full: AtomicUsize,
+    /// Requests answered with 503 after waiting for too long.
+    shed_timed_out: AtomicUsize,
+}
+
+impl Metrics {
+    fn log(&self, running: usize) {
+        tracing::info!(
+            target: "metrics",
+            accepted = self.accepted.load(Ordering::Relaxed),
+            served = self.served.load(Ordering::Relaxed),
+            running,
+            waiting = self.waiting.load(Ordering::Relaxed),
+            shed_queue_full = self.shed_queue_full.load(Ordering::Relaxed),
+            shed_timed_out = self.shed_timed_out.load(Ordering::Relaxed),
+            "server metrics"
+        );
+    }
+}
+
+/// How the server went down after being asked to shut down.
+#[derive(Debug)]
+pub enum Shutdown {
+    /// Every request finished before the deadline.
+    Drained,
+    /// Requests were still being handled when the deadline expired.
+    TimedOut { in_flight: usize },
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Requests served concurrently. Connections only hold a permit while
+    /// one of their requests is being handled, not while idle.
+    pub permits: usize,
+    /// Requests allowed to wait for a permit, those past it are answered
+    /// with `503 Service Unavailable` right away.
+    pub queue_depth: usize,
+    /// How long a request may wait for a permit before being answered with
+    /// `503 Service Unavailable`. Without it, requests wait as long as needed,
+    /// which `QUEUE_WAIT_MS=0` asks for.
+    pub queue_wait: Option<Duration>,
+    /// Value of the `Retry-After` header sent with shed connections.
+    pub retry_after: Duration,
+    /// How long a keep-alive connection may wait for its next request.
+    pub idle_timeout: Duration,
+    /// Requests served on a single connection before it is closed.
+    pub max_requests_per_connection: usize,
+    pub limits: Limits,
+    /// How long open connections have to finish once shutdown starts.
+    pub shutdown_timeout: Duration,
+    /// How often the server metrics are logged.
+    pub metrics_interval: Duration,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let queue_wait =
+            config::env_duration_ms("QUEUE_WAIT_MS", default.queue_wait.unwrap_or_default());
 
-impl<S, F> Server<S, F>
+        Self {
+            permits: config::env_or("PERMITS", default.permits),
+            queue_depth: config::env_or("QUEUE_DEPTH", default.queue_depth),
+            queue_wait: (!queue_wait.is_zero()).then_some(queue_wait),
+            retry_after: config::env_duration_ms("RETRY_AFTER_MS", default.retry_after),
+            idle_timeout: config::env_duration_ms("IDLE_TIMEOUT_MS", default.idle_timeout),
+            max_requests_per_connection: config::env_or(
+                "MAX_REQUESTS_PER_CONNECTION",
+                default.max_requests_per_connection,
+            ),
+            limits: Limits {
+                max_request_line_len: config::env_or(
+                    "MAX_REQUEST_LINE_LEN",
+                    default.limits.max_request_line_len,
+                ),
+                max_headers_len: config::env_or("MAX_HEADERS_LEN", default.limits.max_headers_len),
+                max_headers: config::env_or("MAX_HEADERS", default.limits.max_headers),
+                max_body_len: config::env_or("MAX_BODY_LEN", default.limits.max_body_len),
+            },
+            shutdown_timeout: config::env_duration_ms(
+                "SHUTDOWN_TIMEOUT_MS",
+                default.shutdown_timeout,
+            ),
+            metrics_interval: config::env_duration_ms(
+                "METRICS_INTERVAL_MS",
+                default.metrics_interval,
+            ),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            permits: 1_000,
+            queue_depth: 10_000,
+            queue_wait: Some(Duration::from_secs(1)),
+            retry_after: Duration::from_secs(1),
+            idle_timeout: Duration::from_secs(5),
+            max_requests_per_connection: 1_000,
+            limits: Limits::default(),
+            shutdown_timeout: Duration::from_secs(8),
+            metrics_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl<S, H> Server<S, H>
 where
     S: Clone + Send + Sync + 'static,
-    F: Future<Output = Response> + Send + 'static,
+    H: Handler<S>,
 {
-    pub fn new(state: S, handler: Handler<S, F>) -> Self {
+    pub fn new(state: S, handler: H) -> Self {
+        let config = ServerConfig::default();
         Self {
             state,
             handler,
-            semaphore: Arc::new(Semaphore::new(PERMITS)),
+            semaphore: Arc::new(Semaphore::new(config.permits)),
+            config,
+            stop: CancellationToken::new(),
+            metrics: Metrics::default(),
         }
     }
 
-    pub async fn bind<A: ToSocketAddrs>(self, addr: A) -> io::Result<()> {
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.semaphore = Arc::new(Semaphore::new(config.permits));
+        self.config = config;
+        self
+    }
+
+    /// Serves connections until `shutdown` resolves, then stops accepting,
+    /// lets open connections finish their current request and waits up to
+    /// [`ServerConfig::shutdown_timeout`] for them to close.
+    pub async fn bind<A, F>(self, addr: A, shutdown: F) -> io::Result<Shutdown>
+    where
+        A: ToSocketAddrs,
+        F: Future<Output = ()>,
+    {
         let server = Arc::new(self);
 
         let listener = tokio::net::TcpListener::bind(addr).await?;
         let addr = listener.local_addr()?;
         tracing::info!(target: "listener", ?addr, "server is running");
 
-        let (tx, mut rx) = tokio::sync::mpsc::channel(10_000);
-        let pending = Arc::new(AtomicUsize::new(0));
-        tokio::spawn({
-            let pending = Arc::clone(&pending);
-            async move {
-                loop {
-                    let (socket, addr) = listener.accept().await.unwrap();
-                    pending.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                    tx.send((socket, addr)).await.unwrap();
-                }
-            }
-        });
-
-        let mut now = Instant::now();
-        let mut connections = 0usize;
+        let mut report = tokio::time::interval(server.config.metrics_interval.max(MIN_INTERVAL));
+        report.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
+        let mut shutdown = std::pin::pin!(shutdown);
         loop {
-            let (socket, addr) = rx.recv().await.unwrap();
-            let permit = server.acquire_permit().await;
-
-            connections += 1;
-            let pending = pending.fetch_sub(1, std::sync::atomic::Ordering::Relaxed) - 1;
-            if now.elapsed() > Duration::from_secs(1) {
-                tracing::debug!(
-                    target: "listener",
-                    "{connections}/s with {} tasks running, pending connections: {pending}",
-                    PERMITS - server.semaphore.available_permits()
-                );
-                now = Instant::now();
-                connections = 0;
-            }
+            let accepted = tokio::select! {
+                _ = &mut shutdown => break,
+                _ = report.tick() => {
+                    server.metrics.log(server.running());
+                    continue;
+                }
+                accepted = listener.accept() => accepted,
+            };
 
+            let (socket, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // usually out of file descriptors, give connections
+                    // some time to close before trying again
+                    tracing::error!(target: "listener", %err, "failed to accept connection");
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    continue;
+                }
+            };
+
+            server.metrics.accepted.fetch_add(1, Ordering::Relaxed);
             let server = server.clone();
-            tokio::spawn(async move {
-                let _ = tokio::time::timeout(
-                    crate::TIMEOUT_DURATION,
-                    server.handle_request(socket, addr, permit),
-                )
-                .await;
-            });
+            tokio::spawn(server.handle_connection(socket, addr));
         }
+
+        tracing::info!(target: "listener", "shutting down");
+        server.stop.cancel();
+        drop(listener);
+
+        // idle connections close as soon as they see the stop, the others
+        // once their current request is answered, releasing its permit
+        let in_flight = server.running();
+        tracing::info!(target: "listener", in_flight, "waiting for requests to finish");
+
+        let drain = server.semaphore.acquire_many(server.config.permits as u32);
+        let shutdown = match tokio::time::timeout(server.config.shutdown_timeout, drain).await {
+            Ok(_) => Shutdown::Drained,
+            Err(_) => Shutdown::TimedOut {
+                in_flight: server.running(),
+            },
+        };
+        server.metrics.log(server.running());
+
+        Ok(shutdown)
     }
 
-    #[tracing::instrument(skip(self, socket, permit))]
-    async fn handle_request(
-        self: Arc<Self>,
-        socket: TcpStream,
-        addr: SocketAddr,
-        permit: OwnedSemaphorePermit,
-    ) {
-        let mut codec = ConnectionCodec::default().framed(socket);
-        let req = match codec.next().await.transpose() {
-            Ok(Some(req)) => {
-                tracing::debug!(?req, "received request");
-                req
+    #[tracing::instrument(skip(self, socket))]
+    async fn handle_connection(self: Arc<Self>, socket: TcpStream, addr: SocketAddr) {
+        let mut codec = ConnectionCodec::new(self.config.limits).framed(socket);
+        let mut served = 0usize;
+
+        loop {
+            let next = tokio::select! {
+                _ = self.stop.cancelled() => break,
+                next = tokio::time::timeout(self.config.idle_timeout, codec.next()) => next,
+            };
+
+            let req = match next {
+                Ok(Some(Ok(req))) => {
+                    tracing::debug!(?req, "received request");
+                    req
+                }
+                Ok(Some(Err(RequestError::IoError(err)))) => {
+                    tracing::warn!(%err, "failed to read request");
+                    break;
+                }
+                Ok(Some(Err(err))) => {
+                    tracing::warn!(%err, "rejecting malformed request");
+
+                    let mut resp = err.into_response();
+                    resp.headers_mut().insert(CONNECTION, CLOSE);
+
+                    if let Err(err) = codec.send(resp).await {
+                        tracing::warn!(%err, "failed to send response");
+                    }
+                    break;
+                }
+                Ok(None) if served == 0 => {
+                    tracing::error!("connection ended before request");
+                    break;
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    tracing::debug!(served, "connection idle, closing");
+                    break;
+                }
+            };
+
+            // held until the response is written, idle connections don't
+            // take any
+            let Some(permit) = self.acquire_permit(Instant::now()).await else {
+                self.shed(&mut codec).await;
+                break;
+            };
+
+            served += 1;
+            self.metrics.served.fetch_add(1, Ordering::Relaxed);
+            let keep_alive = served < self.config.max_requests_per_connection
+                && is_keep_alive(&req)
+                && !self.stop.is_cancelled();
+
+            let version = req.version();
+            let mut resp = self.handler.call(req, self.state.clone()).await;
+            // HTTP/1.0 has no chunked encoding, so a streamed body can only
+            // end with the connection
+            let chunked = version != Version::HTTP_10;
+            let keep_alive = keep_alive && (chunked || resp.body().len().is_some());
+
+            resp.headers_mut()
+                .insert(CONNECTION, if keep_alive { KEEP_ALIVE } else { CLOSE });
+
+            // Requests are handled one at a time, so responses to pipelined
+            // requests are written in the order they were received.
+            let sent = send_response(&mut codec, resp, chunked).await;
+            drop(permit);
+            if let Err(err) = sent {
+                tracing::warn!(%err, "failed to send response");
+                break;
             }
-            Ok(None) => {
-                tracing::error!("connection ended before request");
-                return;
+
+            if !keep_alive {
+                break;
             }
-            Err(err) => {
-                tracing::warn!(%err, "failed to read request");
-                return;
+        }
+    }
+
+    /// Requests holding a permit.
+    fn running(&self) -> usize {
+        self.config.permits - self.semaphore.available_permits()
+    }
+
+    /// Waits for a permit to serve a request received at `received_at`,
+    /// giving up right away when [`ServerConfig::queue_depth`] requests are
+    /// already waiting, or once it has waited for longer than
+    /// [`ServerConfig::queue_wait`].
+    async fn acquire_permit(&self, received_at: Instant) -> Option<OwnedSemaphorePermit> {
+        let semaphore = Arc::clone(&self.semaphore);
+        if let Ok(permit) = Arc::clone(&semaphore).try_acquire_owned() {
+            return Some(permit);
+        }
+
+        let waiting = self.metrics.waiting.fetch_add(1, Ordering::Relaxed);
+        let permit = if waiting >= self.config.queue_depth {
+            self.metrics.shed_queue_full.fetch_add(1, Ordering::Relaxed);
+            None
+        } else {
+            match self.config.queue_wait {
+                None => semaphore.acquire_owned().await.ok(),
+                Some(queue_wait) => {
+                    let remaining = queue_wait.saturating_sub(received_at.elapsed());
+                    match tokio::time::timeout(remaining, semaphore.acquire_owned()).await {
+                        Ok(permit) => permit.ok(),
+                        Err(_) => {
+                            self.metrics.shed_timed_out.fetch_add(1, Ordering::Relaxed);
+                            None
+                        }
+                    }
+                }
             }
         };
+        self.metrics.waiting.fetch_sub(1, Ordering::Relaxed);
 
-        let user = req.headers().get(USER_AGENT).unwrap_or_else(|| {
-            static UNKNOWN_AGENT: HeaderValue = HeaderValue::from_static("Unknown");
-            &UNKNOWN_AGENT
-        });
+        permit
+    }
 
-        let path = req.uri().to_string();
-        tracing::info!(
-            target: "requests",
-            method = %req.method(),
-            %path,
-            ?user,
-            r#""{} {path}" by {user:?}"#, req.method()
-        );
+    /// Answers a request the server has no capacity for with
+    /// `503 Service Unavailable`, closing the connection afterwards.
+    async fn shed(&self, codec: &mut Framed<TcpStream, ConnectionCodec>) {
+        tracing::debug!(target: "listener", "shedding request");
 
-        let now = Instant::now();
-        let mut resp = (self.handler)(req, self.state.clone()).await;
-        tracing::debug!(?resp, "handled in {:?}, sending response", now.elapsed());
+        let mut resp = ApiError::Overloaded.into_response();
+        let retry_after = self.config.retry_after.as_secs().max(1);
+        resp.headers_mut().insert(RETRY_AFTER, retry_after.into());
+        resp.headers_mut().insert(CONNECTION, CLOSE);
 
-        const CLOSE: HeaderValue = HeaderValue::from_static("close");
-        resp.headers_mut().append(CONNECTION, CLOSE);
+        if let Err(err) = codec.send(resp).await {
+            tracing::debug!(%err, "failed to send response");
+        }
+    }
+}
 
-        drop(permit);
+/// Writes `resp`, sending a streamed body as it is produced, in chunks when
+/// `chunked` and as it comes otherwise.
+async fn send_response(
+    codec: &mut Framed<TcpStream, ConnectionCodec>,
+    mut resp: Response,
+    chunked: bool,
+) -> Result<(), ResponseError> {
+    let Some(mut stream) = resp.body_mut().take_stream() else {
+        return codec.send(resp).await;
+    };
 
-        if let Err(err) = codec.send(resp).await {
-            tracing::warn!(%err, "failed to send response");
+    if chunked {
+        resp.headers_mut().insert(TRANSFER_ENCODING, CHUNKED);
+    }
+    codec.feed(resp).await?;
+
+    while let Some(chunk) = stream.next().await {
+        codec.send(Chunk::Data(chunk?)).await?;
+    }
+
+    codec.send(Chunk::End).await
+}
+
+/// HTTP/1.1 connections are persistent unless the client asks otherwise,
+/// while HTTP/1.0 ones must opt in with `Connection: keep-alive`.
+fn is_keep_alive(req: &Request) -> bool {
+    let has_token = |token: &str| {
+        req.headers()
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+
+    match req.version() {
+        Version::HTTP_10 => has_token("keep-alive"),
+        _ => !has_token("close"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(version: Version, connection: &[&str]) -> Request {
+        let mut req = http::Request::builder().version(version);
+        for value in connection {
+            req = req.header(CONNECTION, *value);
         }
+        req.body(None).unwrap()
     }
 
-    async fn acquire_permit(&self) -> OwnedSemaphorePermit {
-        loop {
-            if let Ok(permit) = Arc::clone(&self.semaphore).try_acquire_owned() {
-                break permit;
-            }
+    #[test]
+    fn keeps_alive_by_version_and_connection() {
+        let cases = [
+            (Version::HTTP_11, &[][..], true),
+            (Version::HTTP_11, &["keep-alive"][..], true),
+            (Version::HTTP_11, &["close"][..], false),
+            (Version::HTTP_11, &["Upgrade, Close"][..], false),
+            (Version::HTTP_11, &["upgrade", "close"][..], false),
+            (Version::HTTP_10, &[][..], false),
+            (Version::HTTP_10, &["close"][..], false),
+            (Version::HTTP_10, &["Keep-Alive"][..], true),
+            (Version::HTTP_10, &["foo, keep-alive"][..], true),
+        ];
 
-            let mut factor = 1;
-            loop {
-                const BACKOFF: Duration = Duration::from_millis(50);
-                tokio::time::sleep(factor * BACKOFF).await;
-                factor *= 2;
-                let available_permits = self.semaphore.available_permits();
-                if available_permits >= PERMITS / 100 {
-                    break;
-                }
-            }
+        for (version, connection, keep_alive) in cases {
+            let req = request(version, connection);
+            assert_eq!(
+                is_keep_alive(&req),
+                keep_alive,
+                "{version:?} {connection:?}"
+            );
         }
     }
 }